@@ -0,0 +1,195 @@
+//! API key authentication of the router's callers
+//!
+//! Clients are listed in `MODEL_DIR/clients.yaml`, each with the secret holding its key and the
+//! models it may use. The file is read once at startup, and without it every request is let
+//! through. Callers send their key as `Authorization: Bearer <key>`, and the header is removed as
+//! soon as it has been checked so the key never reaches a handler or a log.
+//!
+//! Admin endpoints such as `/chat/reload` are open to clients marked `admin`, and to the admin
+//! key in `ROUTER_ADMIN_KEY`. They are closed to everyone else.
+
+use crate::chat::errors::ErrorResponse;
+use crate::secret_manager::Secrets;
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::{Json, OriginalUri, State},
+    http::{header, HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Secret holding the key for the admin endpoints
+const ADMIN_KEY_SECRET: &str = "ROUTER_ADMIN_KEY";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientConfig {
+    /// Name of the secret holding the client's key
+    pub key_secret: String,
+    /// The models the client may use, all of them when unset
+    #[serde(default)]
+    pub models: Option<Vec<String>>,
+    /// Whether the client may use the admin endpoints
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// The client a request was authenticated as, added to the request's extensions
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub name: String,
+    models: Option<Vec<String>>,
+    pub admin: bool,
+}
+
+impl Client {
+    pub fn allows(&self, model: &str) -> bool {
+        match &self.models {
+            Some(models) => models.iter().any(|allowed| allowed == model),
+            None => true,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Auth {
+    clients: Arc<BTreeMap<String, ClientConfig>>,
+    secrets: Secrets,
+}
+
+impl Auth {
+    /// Read `clients.json`, `clients.yaml` or `clients.yml` from `model_dir`. Without one there
+    /// is no authentication.
+    pub async fn load(model_dir: &Path, secrets: Secrets) -> anyhow::Result<Option<Self>> {
+        for extension in ["json", "yaml", "yml"] {
+            let path = model_dir.join("clients").with_extension(extension);
+            if !path.exists() {
+                continue;
+            }
+            let file = std::fs::File::open(&path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            let clients: BTreeMap<String, ClientConfig> = match extension {
+                "json" => serde_json::from_reader(file)?,
+                _ => serde_yaml::from_reader(file)?,
+            };
+            tracing::info!(
+                "Loaded {} clients from {}, requiring API keys",
+                clients.len(),
+                path.display()
+            );
+            for (name, client) in &clients {
+                if secrets.get_secret(&client.key_secret).await.is_none() {
+                    tracing::error!(
+                        "Missing key secret {} of client {}",
+                        client.key_secret,
+                        name
+                    );
+                }
+            }
+            return Ok(Some(Self {
+                clients: Arc::new(clients),
+                secrets,
+            }));
+        }
+        Ok(None)
+    }
+
+    /// The client holding `key`. Keys are read from the secrets on every request, so a rotated
+    /// key takes effect straight away.
+    async fn client(&self, key: &str) -> Option<Client> {
+        for (name, client) in self.clients.iter() {
+            let Some(secret) = self.secrets.get_secret(&client.key_secret).await else {
+                continue;
+            };
+            if !secret.is_empty() && keys_match(secret.as_bytes(), key.as_bytes()) {
+                return Some(Client {
+                    name: name.clone(),
+                    models: client.models.clone(),
+                    admin: client.admin,
+                });
+            }
+        }
+        None
+    }
+}
+
+/// Compare keys in constant time, so the time taken doesn't give away how much of a key matched
+fn keys_match(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The key in an `Authorization: Bearer <key>` header
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Whether `key` is the admin key
+async fn is_admin_key(secrets: &Secrets, key: &str) -> bool {
+    let Some(admin_key) = secrets.get_secret(ADMIN_KEY_SECRET).await else {
+        return false;
+    };
+    !admin_key.is_empty() && keys_match(admin_key.as_bytes(), key.as_bytes())
+}
+
+/// Whether the request carries the admin key
+pub async fn is_admin(secrets: &Secrets, headers: &HeaderMap) -> bool {
+    match bearer(headers) {
+        Some(key) => is_admin_key(secrets, key).await,
+        None => false,
+    }
+}
+
+pub fn unauthorized(error: &str) -> Response {
+    let mut response = (StatusCode::UNAUTHORIZED, Json(ErrorResponse::new(error))).into_response();
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+    response
+}
+
+/// Middleware letting through requests with a client's key, and adding the `Client` to them. The
+/// admin key is let through as an admin client that may use every model.
+pub async fn authenticate(
+    State(auth): State<Auth>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    // Nested routers only see the rest of the path
+    let path = match request.extensions().get::<OriginalUri>() {
+        Some(OriginalUri(uri)) => uri.path().to_string(),
+        None => request.uri().path().to_string(),
+    };
+    if !request.headers().contains_key(header::AUTHORIZATION) {
+        return unauthorized("Missing API key");
+    }
+    let key = bearer(request.headers()).map(str::to_string);
+    request.headers_mut().remove(header::AUTHORIZATION);
+    let client = match key.as_deref() {
+        Some(key) => match auth.client(key).await {
+            Some(client) => Some(client),
+            None if is_admin_key(&auth.secrets, key).await => Some(Client {
+                name: "admin".to_string(),
+                models: None,
+                admin: true,
+            }),
+            None => None,
+        },
+        None => None,
+    };
+    let Some(client) = client else {
+        tracing::warn!("Rejected a request to {} with an invalid API key", path);
+        return unauthorized("Invalid API key");
+    };
+    tracing::debug!("Request to {} from client {}", path, client.name);
+    request.extensions_mut().insert(client);
+    next.run(request).await
+}
@@ -0,0 +1,199 @@
+//! Several API keys for one model
+//!
+//! A model that lists `api_key_secrets` spreads its requests over the keys round robin. A key
+//! that gets rate limited is benched until the upstream's `Retry-After`, and the request moves
+//! on to the next key straight away. Keys are read from the secrets as they are needed, and a
+//! missing one is skipped.
+
+use super::errors::ModelError;
+use super::retry::{retry_after, Attempt, Failure};
+use crate::metrics;
+use crate::secret_manager::Secrets;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// How long a rate limited key is benched when the upstream doesn't say
+const DEFAULT_BENCH: Duration = Duration::from_secs(60);
+
+/// The longest a key is benched for, whatever the upstream asks
+const MAX_BENCH: Duration = Duration::from_secs(60 * 60);
+
+/// Why [`ApiKeys::send`] got no response
+#[derive(Debug, Error)]
+pub enum SendError {
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+    /// The model has keys, but none of the ones tried are in the secrets
+    #[error("Missing Auth")]
+    MissingAuth,
+}
+
+impl Failure for SendError {
+    fn is_final(&self) -> bool {
+        match self {
+            SendError::Request(e) => e.is_final(),
+            SendError::MissingAuth => true,
+        }
+    }
+}
+
+impl From<SendError> for ModelError {
+    fn from(e: SendError) -> Self {
+        match e {
+            SendError::Request(_) => ModelError::UpstreamModelError,
+            SendError::MissingAuth => ModelError::Other("Missing Auth".to_string()),
+        }
+    }
+}
+
+/// Which of a model's keys goes next, and which are benched
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+    next: AtomicUsize,
+    /// When each benched key, by its secret, can be used again
+    benched_until: Mutex<HashMap<String, Instant>>,
+}
+
+impl ApiKeys {
+    /// The secrets to try for a request, in order. These are the keys that aren't benched, round
+    /// robin from the next one, or when every key is benched the one back soonest.
+    fn order<'a>(&self, secrets: &[&'a str]) -> Vec<&'a str> {
+        if secrets.is_empty() {
+            return Vec::new();
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let mut benched_until = self.benched_until.lock().unwrap();
+        benched_until.retain(|_, until| *until > now);
+        let order: Vec<&str> = (0..secrets.len())
+            .map(|i| secrets[(next + i) % secrets.len()])
+            .filter(|secret| !benched_until.contains_key(*secret))
+            .collect();
+        if !order.is_empty() {
+            return order;
+        }
+        let soonest = secrets
+            .iter()
+            .min_by_key(|secret| benched_until.get(**secret))
+            .unwrap();
+        vec![soonest]
+    }
+
+    /// Read the first of the model's keys there is. Fails only when the model has keys and none
+    /// of them are in the secrets.
+    pub async fn lookup<'a>(
+        &self,
+        model: &str,
+        secrets: &Secrets,
+        names: &[&'a str],
+    ) -> Result<Keys<'a>, ModelError> {
+        let mut first = None;
+        for name in names {
+            if let Some(key) = read(model, secrets, name).await {
+                first = Some((*name, key));
+                break;
+            }
+        }
+        if first.is_none() && !names.is_empty() {
+            return Err(ModelError::Other("Missing Auth".to_string()));
+        }
+        Ok(Keys {
+            secrets: secrets.clone(),
+            names: names.to_vec(),
+            first,
+        })
+    }
+
+    fn bench(&self, model: &str, secret: &str, duration: Duration) {
+        let duration = duration.min(MAX_BENCH);
+        tracing::warn!(
+            "API key {} of {} is rate limited, benching it for {}s",
+            secret,
+            model,
+            duration.as_secs()
+        );
+        metrics::API_KEY_BENCHED
+            .with_label_values(&[model, secret])
+            .inc();
+        self.benched_until
+            .lock()
+            .unwrap()
+            .insert(secret.to_string(), Instant::now() + duration);
+    }
+
+    /// Run `attempt` with each of `keys` in turn until one isn't rate limited, benching the ones
+    /// that are. The order is picked on every call, so a retry starts past the keys benched by
+    /// the attempt before it. The last key's response is returned as is, and without keys
+    /// `attempt` runs once with none. When none of the keys tried can be read, the last rate
+    /// limited response is returned, or `MissingAuth` if there wasn't one.
+    pub async fn send<T, F, Fut>(
+        &self,
+        model: &str,
+        keys: &Keys<'_>,
+        mut attempt: F,
+    ) -> Result<T, SendError>
+    where
+        T: Attempt,
+        F: FnMut(Option<&str>) -> Fut,
+        Fut: Future<Output = reqwest::Result<T>>,
+    {
+        let mut limited = None;
+        for secret in self.order(&keys.names) {
+            let key = match &keys.first {
+                Some((name, key)) if *name == secret => key.clone(),
+                _ => match read(model, &keys.secrets, secret).await {
+                    Some(key) => key,
+                    None => continue,
+                },
+            };
+            metrics::API_KEY_REQUESTS
+                .with_label_values(&[model, secret])
+                .inc();
+            let result = attempt(Some(&key)).await;
+            if let Ok(response) = &result {
+                let response = response.response();
+                if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    let duration = retry_after(response).unwrap_or(DEFAULT_BENCH);
+                    self.bench(model, secret, duration);
+                    limited = Some(result);
+                    continue;
+                }
+            }
+            return Ok(result?);
+        }
+        match limited {
+            Some(result) => Ok(result?),
+            None if keys.names.is_empty() => Ok(attempt(None).await?),
+            None => Err(SendError::MissingAuth),
+        }
+    }
+}
+
+/// A model's keys for one request. Only the first one there is gets read up front, the others
+/// are read as the attempts get to them.
+pub struct Keys<'a> {
+    secrets: Secrets,
+    names: Vec<&'a str>,
+    /// The first key there is, by its secret
+    first: Option<(&'a str, String)>,
+}
+
+impl Keys<'_> {
+    /// The first key there is, `None` for models without keys
+    pub fn first(&self) -> Option<&str> {
+        self.first.as_ref().map(|(_, key)| key.as_str())
+    }
+}
+
+/// Read a key from the secrets, warning when it's missing so the request can go on without it
+async fn read(model: &str, secrets: &Secrets, name: &str) -> Option<String> {
+    let key = secrets.get_secret(name).await;
+    if key.is_none() {
+        tracing::warn!("API key {} of {} is missing, skipping it", name, model);
+    }
+    key
+}
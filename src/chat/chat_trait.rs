@@ -1,10 +1,24 @@
-use super::{ChatRequest, ChatResponse, History};
-use crate::{chat::errors::ModelError, secret_manager::Secrets};
+use super::{ChatRequest, ChatResponse, GenerationDetails, History};
+use crate::{
+    chat::{errors::ModelError, stream::ChatStream},
+    secret_manager::Secrets,
+};
 use async_trait::async_trait;
 
 #[async_trait]
 pub trait ChatLlm {
     fn name(&self) -> &str;
+
+    /// Called once after the model is built from its config, before it serves any requests
+    async fn init(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Names of the secrets the model reads, checked when the models are loaded
+    fn secrets(&self) -> Vec<&str> {
+        Vec::new()
+    }
+
     async fn chat(
         &self,
         secrets: Secrets,
@@ -12,6 +26,40 @@ pub trait ChatLlm {
         system: Option<String>,
         history: Vec<History>,
     ) -> Result<String, ModelError>;
+
+    /// Like `chat`, but also returns the details of the generation for backends that report them
+    async fn chat_with_details(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<(String, Option<GenerationDetails>), ModelError> {
+        let generation = self.chat(secrets, prompt, system, history).await?;
+        Ok((generation, None))
+    }
+
+    /// Stream the generation as the upstream produces it. Backends without native
+    /// streaming send the whole generation as a single token.
+    async fn chat_stream(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<ChatStream, ModelError> {
+        let generation = self.chat(secrets, prompt, system, history).await?;
+        Ok(Box::pin(futures::stream::once(
+            async move { Ok(generation) },
+        )))
+    }
+
+    /// Check that the model can serve requests, without spending tokens or counting against the
+    /// upstream's rate limits. Backends with nothing that cheap to call are taken to be healthy.
+    async fn health_check(&self, _secrets: Secrets) -> Result<(), ModelError> {
+        Ok(())
+    }
+
     fn system_limit(&self) -> usize {
         0
     }
@@ -30,8 +78,24 @@ pub trait ChatLlm {
 }
 
 impl ChatRequest {
-    /// Age out old history and trim the prompt and system to fit the model limits
-    pub fn trim(&mut self, llm: &dyn ChatLlm) -> Result<(), ModelError> {
+    /// Tokens the request sends to the model, counted the same way as in `trim`
+    pub fn count_tokens(&self, llm: &dyn ChatLlm) -> usize {
+        let system_tokens = self
+            .system
+            .as_ref()
+            .map(|s| llm.count_tokens(s))
+            .unwrap_or(0);
+        let history_tokens: usize = self
+            .history
+            .iter()
+            .map(|h| llm.count_tokens(&h.prompt) + llm.count_tokens(&h.generation))
+            .sum();
+        system_tokens + llm.count_tokens(&self.prompt) + history_tokens
+    }
+
+    /// Age out old history and trim the prompt and system to fit the model limits. Returns how
+    /// many history items were dropped.
+    pub fn trim(&mut self, llm: &dyn ChatLlm) -> Result<usize, ModelError> {
         tracing::trace!("Trimming request");
         let mut total_tokens = 0;
         let system_tokens = self
@@ -84,8 +148,9 @@ impl ChatRequest {
             new_history.len(),
             old_history_len
         );
+        let trimmed = old_history_len as usize - new_history.len();
         self.history = new_history;
-        Ok(())
+        Ok(trimmed)
     }
 }
 
@@ -101,6 +166,8 @@ impl ChatResponse {
             Some(Self {
                 generation: generation.to_string(),
                 uuid: uuid.to_string(),
+                details: None,
+                model: None,
             })
         } else {
             None
@@ -0,0 +1,255 @@
+//! Circuit breakers around the upstream models
+//!
+//! A model whose upstream keeps failing is cut off for a while, so requests fail fast instead of
+//! each waiting on a dead server. Once the circuit has been open for `open_seconds` a single
+//! request is let through to probe the upstream, closing the circuit again if it succeeds.
+
+use super::errors::ModelError;
+use crate::metrics;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+fn default_failures() -> u32 {
+    5
+}
+
+fn default_window_seconds() -> u64 {
+    60
+}
+
+fn default_open_seconds() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CircuitBreakerConfig {
+    /// Upstream failures within `window_seconds` that open the circuit, 0 never opens it
+    #[serde(default = "default_failures")]
+    pub failures: u32,
+    #[serde(default = "default_window_seconds")]
+    pub window_seconds: u64,
+    /// How long the circuit stays open before probing the upstream
+    #[serde(default = "default_open_seconds")]
+    pub open_seconds: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failures: default_failures(),
+            window_seconds: default_window_seconds(),
+            open_seconds: default_open_seconds(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    /// Requests fail fast without reaching the upstream
+    Open,
+    /// One request at a time is let through to probe the upstream
+    HalfOpen,
+}
+
+impl CircuitState {
+    /// The value of the state metric
+    fn gauge(&self) -> i64 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::HalfOpen => 1,
+            CircuitState::Open => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CircuitStatus {
+    pub state: CircuitState,
+    /// Upstream failures within the window
+    pub recent_failures: usize,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    failures: VecDeque<Instant>,
+    /// When the circuit opened, or when the current probe was let through while half open
+    since: Instant,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    labels: [String; 2],
+    circuit: Mutex<Circuit>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig, model: &str, backend: &str) -> Self {
+        let breaker = Self {
+            config,
+            labels: [model.to_string(), backend.to_string()],
+            circuit: Mutex::new(Circuit {
+                state: CircuitState::Closed,
+                failures: VecDeque::new(),
+                since: Instant::now(),
+            }),
+        };
+        breaker.set_state(&mut breaker.circuit.lock().unwrap(), CircuitState::Closed);
+        breaker
+    }
+
+    fn set_state(&self, circuit: &mut Circuit, state: CircuitState) {
+        if circuit.state != state {
+            tracing::warn!("Circuit for {} is now {:?}", self.labels[0], state);
+        }
+        circuit.state = state;
+        circuit.since = Instant::now();
+        metrics::CIRCUIT_STATE
+            .with_label_values(&[&self.labels[0], &self.labels[1]])
+            .set(state.gauge());
+    }
+
+    /// Set the state gauge again, after a breaker built for a reload of the same model reset it
+    pub fn publish(&self) {
+        let circuit = self.circuit.lock().unwrap();
+        metrics::CIRCUIT_STATE
+            .with_label_values(&[&self.labels[0], &self.labels[1]])
+            .set(circuit.state.gauge());
+    }
+
+    /// Let a request through to the upstream, or `None` while the circuit is open. The outcome
+    /// of the request is passed to [`Permit::record`], or the permit is released if the request
+    /// never reaches the upstream.
+    pub fn allow(self: &Arc<Self>) -> Option<Permit> {
+        let mut circuit = self.circuit.lock().unwrap();
+        let open_for = Duration::from_secs(self.config.open_seconds);
+        let probe = match circuit.state {
+            CircuitState::Closed => false,
+            CircuitState::Open if circuit.since.elapsed() >= open_for => {
+                self.set_state(&mut circuit, CircuitState::HalfOpen);
+                true
+            }
+            CircuitState::Open => return None,
+            // A probe still waiting on the upstream after `open_seconds` is replaced
+            CircuitState::HalfOpen if circuit.since.elapsed() >= open_for => {
+                circuit.since = Instant::now();
+                true
+            }
+            CircuitState::HalfOpen => return None,
+        };
+        Some(Permit {
+            breaker: self.clone(),
+            probe,
+            done: false,
+        })
+    }
+
+    /// A probe dropped before it reported back, say because the request ran past its deadline,
+    /// counts as a failure so the circuit doesn't stay half open
+    fn abandon(&self) {
+        let mut circuit = self.circuit.lock().unwrap();
+        if circuit.state == CircuitState::HalfOpen {
+            circuit.failures.push_back(Instant::now());
+            self.set_state(&mut circuit, CircuitState::Open);
+        }
+    }
+
+    /// Give back a probe that was turned away before reaching the upstream, so the next request
+    /// probes straight away
+    fn release(&self) {
+        let mut circuit = self.circuit.lock().unwrap();
+        if circuit.state == CircuitState::HalfOpen {
+            self.set_state(&mut circuit, CircuitState::Open);
+            let open_for = Duration::from_secs(self.config.open_seconds);
+            circuit.since = Instant::now()
+                .checked_sub(open_for)
+                .unwrap_or(circuit.since);
+        }
+    }
+
+    /// Count the outcome of an allowed request. Only upstream errors and timeouts are failures,
+    /// the rest show the upstream is answering.
+    fn record<T>(&self, result: &Result<T, ModelError>) {
+        let failed = matches!(
+            result,
+            Err(ModelError::UpstreamModelError | ModelError::Timeout)
+        );
+        let mut circuit = self.circuit.lock().unwrap();
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window_seconds);
+        while matches!(circuit.failures.front(), Some(failure) if now - *failure > window) {
+            circuit.failures.pop_front();
+        }
+        match (circuit.state, failed) {
+            (CircuitState::HalfOpen, false) => {
+                circuit.failures.clear();
+                self.set_state(&mut circuit, CircuitState::Closed);
+            }
+            (CircuitState::HalfOpen, true) => {
+                circuit.failures.push_back(now);
+                self.set_state(&mut circuit, CircuitState::Open);
+            }
+            (CircuitState::Closed, true) => {
+                circuit.failures.push_back(now);
+                if self.config.failures > 0
+                    && circuit.failures.len() >= self.config.failures as usize
+                {
+                    self.set_state(&mut circuit, CircuitState::Open);
+                }
+            }
+            // Requests let through before the circuit opened don't change it
+            (CircuitState::Closed, false) | (CircuitState::Open, _) => {}
+        }
+    }
+
+    pub fn status(&self) -> CircuitStatus {
+        let circuit = self.circuit.lock().unwrap();
+        let window = Duration::from_secs(self.config.window_seconds);
+        CircuitStatus {
+            state: circuit.state,
+            recent_failures: circuit
+                .failures
+                .iter()
+                .filter(|failure| failure.elapsed() <= window)
+                .count(),
+        }
+    }
+}
+
+/// A request let through by [`CircuitBreaker::allow`]. It can be held for as long as the
+/// upstream is streaming, and is recorded once the stream ends.
+#[derive(Debug)]
+pub struct Permit {
+    breaker: Arc<CircuitBreaker>,
+    /// Whether this is the one request probing a half open circuit
+    probe: bool,
+    done: bool,
+}
+
+impl Permit {
+    pub fn record<T>(mut self, result: &Result<T, ModelError>) {
+        self.breaker.record(result);
+        self.done = true;
+    }
+
+    /// For a request turned away before reaching the upstream, which says nothing about it
+    pub fn release(mut self) {
+        if self.probe {
+            self.breaker.release();
+        }
+        self.done = true;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if self.probe && !self.done {
+            self.breaker.abandon();
+        }
+    }
+}
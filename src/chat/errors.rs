@@ -1,3 +1,4 @@
+use super::quota::QuotaStatus;
 use axum::{extract::Json, response::IntoResponse};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -5,9 +6,20 @@ use thiserror::Error;
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ErrorResponse {
     error: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quota: Option<QuotaStatus>,
 }
 
-#[derive(Error, Debug, Deserialize, Serialize)]
+impl ErrorResponse {
+    pub fn new(error: impl Into<String>) -> Self {
+        Self {
+            error: error.into(),
+            quota: None,
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, Deserialize, Serialize)]
 pub enum ModelError {
     #[error("Model not found")]
     ModelNotFound,
@@ -21,14 +33,20 @@ pub enum ModelError {
     PromptTooLong,
     #[error("Preprompt was too long")]
     SystemTooLong,
+    #[error("Quota for {} exceeded", .0.identity)]
+    QuotaExceeded(QuotaStatus),
+    #[error("Blocked by the upstream content filter")]
+    ContentFiltered,
+    #[error("Timed out waiting on the upstream model")]
+    Timeout,
     #[error("Other error: {0}")]
     Other(String),
 }
 
-// This should be improved
-impl IntoResponse for ModelError {
-    fn into_response(self) -> axum::response::Response {
-        let (code, reason) = match self {
+impl ModelError {
+    // This should be improved
+    fn status_and_reason(&self) -> (reqwest::StatusCode, &'static str) {
+        match self {
             ModelError::UpstreamModelError => (
                 reqwest::StatusCode::INTERNAL_SERVER_ERROR,
                 "Upstream model error",
@@ -37,6 +55,9 @@ impl IntoResponse for ModelError {
                 reqwest::StatusCode::TOO_MANY_REQUESTS,
                 "Rate limit exceeded",
             ),
+            ModelError::QuotaExceeded(_) => {
+                (reqwest::StatusCode::TOO_MANY_REQUESTS, "Quota exceeded")
+            }
             ModelError::HistoryPromptTooLong(_) => (
                 reqwest::StatusCode::UNPROCESSABLE_ENTITY,
                 "Historical prompt too long",
@@ -48,15 +69,69 @@ impl IntoResponse for ModelError {
                 reqwest::StatusCode::UNPROCESSABLE_ENTITY,
                 "System prompt too long",
             ),
+            ModelError::ContentFiltered => (
+                reqwest::StatusCode::UNPROCESSABLE_ENTITY,
+                "Content filtered",
+            ),
+            ModelError::Timeout => (reqwest::StatusCode::GATEWAY_TIMEOUT, "Request timed out"),
             ModelError::Other(_) => (reqwest::StatusCode::INTERNAL_SERVER_ERROR, "Other error"),
             ModelError::ModelNotFound => (reqwest::StatusCode::NOT_FOUND, "Model not found"),
-        };
+        }
+    }
 
-        let mut response = Json(ErrorResponse {
+    /// Errors another attempt, or another model, might not hit
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ModelError::UpstreamModelError | ModelError::RateLimitExceeded(_) | ModelError::Timeout
+        )
+    }
+
+    /// The variant name, for metric labels
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ModelError::ModelNotFound => "model_not_found",
+            ModelError::UpstreamModelError => "upstream_model_error",
+            ModelError::HistoryPromptTooLong(_) => "history_prompt_too_long",
+            ModelError::RateLimitExceeded(_) => "rate_limit_exceeded",
+            ModelError::PromptTooLong => "prompt_too_long",
+            ModelError::SystemTooLong => "system_too_long",
+            ModelError::QuotaExceeded(_) => "quota_exceeded",
+            ModelError::ContentFiltered => "content_filtered",
+            ModelError::Timeout => "timeout",
+            ModelError::Other(_) => "other",
+        }
+    }
+
+    pub fn error_response(&self) -> ErrorResponse {
+        let (_, reason) = self.status_and_reason();
+        let quota = match self {
+            ModelError::QuotaExceeded(status) => Some(status.clone()),
+            _ => None,
+        };
+        ErrorResponse {
             error: reason.to_string(),
-        })
-        .into_response();
+            quota,
+        }
+    }
+}
+
+impl IntoResponse for ModelError {
+    fn into_response(self) -> axum::response::Response {
+        let (code, _) = self.status_and_reason();
+        let mut response = Json(self.error_response()).into_response();
         *response.status_mut() = code;
+        // Retry-After is in whole seconds, round up so a retry doesn't land early
+        let retry_after = match &self {
+            ModelError::RateLimitExceeded(wait) => Some(wait.saturating_add(999) / 1000),
+            ModelError::QuotaExceeded(status) => Some(status.resets_in().max(1)),
+            _ => None,
+        };
+        if let Some(seconds) = retry_after {
+            response
+                .headers_mut()
+                .insert(reqwest::header::RETRY_AFTER, seconds.into());
+        }
         response
     }
 }
@@ -0,0 +1,177 @@
+//! Active health checks of the upstream models
+//!
+//! A background prober runs every model's `health_check` on an interval and keeps the latest
+//! result for each, served on `/health/models`. Models that haven't been checked yet count as
+//! healthy. The models' circuit breakers are served on `/health/circuits`. Both list only the
+//! models the client may use, and need an API key when clients are configured.
+
+use super::{
+    circuit_breaker::CircuitStatus, errors::ErrorResponse, reload::ReloadableModels, ChatState,
+};
+use crate::auth::Client;
+use crate::secret_manager::Secrets;
+use axum::{
+    extract::{Extension, Json, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Checks are cut off at the probe interval, or this if it's shorter
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelHealth {
+    pub healthy: bool,
+    /// Unix time in seconds of the check
+    pub checked_at: u64,
+    pub latency_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The latest check of every model, `null` for models not checked yet
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ModelsHealthResponse {
+    pub models: BTreeMap<String, Option<ModelHealth>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CircuitsResponse {
+    pub models: BTreeMap<String, CircuitStatus>,
+}
+
+#[derive(Clone, Default)]
+pub struct HealthStatus {
+    checks: Arc<RwLock<HashMap<String, ModelHealth>>>,
+    /// Leave unhealthy models out of `/chat/models`
+    pub hide_unhealthy: bool,
+}
+
+impl HealthStatus {
+    pub fn new(hide_unhealthy: bool) -> Self {
+        Self {
+            checks: Default::default(),
+            hide_unhealthy,
+        }
+    }
+
+    pub fn get(&self, model: &str) -> Option<ModelHealth> {
+        self.checks.read().unwrap().get(model).cloned()
+    }
+
+    pub fn is_healthy(&self, model: &str) -> bool {
+        self.get(model).map(|health| health.healthy).unwrap_or(true)
+    }
+
+    /// Check every model every `interval`, starting now
+    pub fn probe(
+        &self,
+        chat_models: ReloadableModels,
+        secrets: Secrets,
+        interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let checks = self.checks.clone();
+        tokio::spawn(async move {
+            loop {
+                let results = chat_models
+                    .current()
+                    .check_health(secrets.clone(), interval.min(HEALTH_CHECK_TIMEOUT))
+                    .await;
+                for (model, health) in &results {
+                    if !health.healthy {
+                        tracing::warn!(
+                            "{} failed its health check: {}",
+                            model,
+                            health.error.as_deref().unwrap_or_default()
+                        );
+                    }
+                }
+                // Replacing the whole map drops models removed by a reload
+                *checks.write().unwrap() = results;
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+}
+
+/// Whether the client, if any, may use `model`
+fn allowed(client: &Option<Extension<Client>>, model: &str) -> bool {
+    match client {
+        Some(Extension(client)) => client.allows(model),
+        None => true,
+    }
+}
+
+async fn models_health(
+    State(chat_state): State<ChatState>,
+    client: Option<Extension<Client>>,
+) -> Response {
+    tracing::trace!("models_health called");
+    let models = match chat_state.chat_models.current().models().await {
+        Ok(models) => models.models,
+        Err(e) => return e.into_response(),
+    };
+    let models = models
+        .into_iter()
+        .filter(|model| allowed(&client, model))
+        .map(|model| {
+            let health = chat_state.health.get(&model);
+            (model, health)
+        })
+        .collect();
+    Json(ModelsHealthResponse { models }).into_response()
+}
+
+async fn circuits(
+    State(chat_state): State<ChatState>,
+    client: Option<Extension<Client>>,
+) -> Response {
+    tracing::trace!("circuits called");
+    let models = chat_state
+        .chat_models
+        .current()
+        .circuits()
+        .into_iter()
+        .filter(|(model, _)| allowed(&client, model))
+        .collect();
+    Json(CircuitsResponse { models }).into_response()
+}
+
+/// Ready while at least one model is healthy
+async fn ready(State(chat_state): State<ChatState>) -> Response {
+    tracing::trace!("ready called");
+    let models = match chat_state.chat_models.current().models().await {
+        Ok(models) => models.models,
+        Err(e) => return e.into_response(),
+    };
+    if models
+        .iter()
+        .any(|model| chat_state.health.is_healthy(model))
+    {
+        "Ok".into_response()
+    } else {
+        let error = ErrorResponse::new("No healthy models");
+        (StatusCode::SERVICE_UNAVAILABLE, Json(error)).into_response()
+    }
+}
+
+/// The health of the router as a whole, open to everyone
+pub fn health_router(chat_state: ChatState) -> Router {
+    Router::new()
+        .route("/health/ready", get(ready))
+        .with_state(chat_state)
+}
+
+/// The health of each model, which goes behind the same authentication as the chat routes
+pub fn models_health_router(chat_state: ChatState) -> Router {
+    Router::new()
+        .route("/health/models", get(models_health))
+        .route("/health/circuits", get(circuits))
+        .with_state(chat_state)
+}
@@ -0,0 +1,140 @@
+//! HTTP clients for the backends
+//!
+//! Each model that talks to an upstream over HTTP holds a client, configured by its entry.
+//! Models with the same settings share one client, and with it a pool of connections. Clients
+//! are built afresh for each load of the models, so a reload reads a changed CA bundle again and
+//! the clients of models that are gone are dropped with them.
+
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const USER_AGENT: &str = concat!("llm_router/", env!("CARGO_PKG_VERSION"));
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Long enough for a streamed generation, which has to finish within it too
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// The clients built for the current load of the models, by the settings they were built with
+static CLIENTS: Lazy<Mutex<HashMap<HttpClientConfig, reqwest::Client>>> =
+    Lazy::new(Default::default);
+
+/// Build new clients from here on, handing back the ones built so far. Models already loaded
+/// keep the clients they hold.
+pub fn take_clients() -> HashMap<HttpClientConfig, reqwest::Client> {
+    std::mem::take(&mut *CLIENTS.lock().unwrap())
+}
+
+/// Go back to the clients [`take_clients`] handed back, after a load that failed
+pub fn restore_clients(clients: HashMap<HttpClientConfig, reqwest::Client>) {
+    *CLIENTS.lock().unwrap() = clients;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct HttpClientConfig {
+    /// How long to wait for a connection to the upstream, 10 seconds by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_timeout_ms: Option<u64>,
+    /// How long each request to the upstream may take, reading the whole response included, 10
+    /// minutes by default. A retry gets the time afresh.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Idle connections kept open to each host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_max_idle_per_host: Option<usize>,
+    /// How long an idle connection is kept open
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_idle_timeout_secs: Option<u64>,
+    /// Interval of TCP keep-alive probes on open connections
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_keepalive_secs: Option<u64>,
+    /// Proxy for every request, otherwise `HTTP_PROXY` and `HTTPS_PROXY` are used when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// PEM file of extra certificate authorities to trust, for self-signed upstreams
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_bundle: Option<String>,
+    /// Defaults to `llm_router/<version>`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+impl HttpClientConfig {
+    fn build(&self) -> anyhow::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(self.user_agent.as_deref().unwrap_or(USER_AGENT))
+            .connect_timeout(
+                self.connect_timeout_ms
+                    .map_or(DEFAULT_CONNECT_TIMEOUT, Duration::from_millis),
+            )
+            .timeout(
+                self.timeout_ms
+                    .map_or(DEFAULT_TIMEOUT, Duration::from_millis),
+            );
+        if let Some(max_idle) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(idle_timeout) = self.pool_idle_timeout_secs {
+            builder = builder.pool_idle_timeout(Duration::from_secs(idle_timeout));
+        }
+        if let Some(keepalive) = self.tcp_keepalive_secs {
+            builder = builder.tcp_keepalive(Duration::from_secs(keepalive));
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy).context("Invalid proxy")?);
+        }
+        if let Some(ca_bundle) = &self.ca_bundle {
+            let pem = std::fs::read(ca_bundle)
+                .with_context(|| format!("Failed to read CA bundle {}", ca_bundle))?;
+            for certificate in reqwest::Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("Invalid CA bundle {}", ca_bundle))?
+            {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        Ok(builder.build()?)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "HttpClientConfig")]
+pub struct HttpClient {
+    config: HttpClientConfig,
+    client: reqwest::Client,
+}
+
+impl TryFrom<HttpClientConfig> for HttpClient {
+    type Error = String;
+
+    fn try_from(config: HttpClientConfig) -> Result<Self, Self::Error> {
+        let mut clients = CLIENTS.lock().unwrap();
+        let client = match clients.get(&config) {
+            Some(client) => client.clone(),
+            None => {
+                let client = config
+                    .build()
+                    .map_err(|e| format!("Failed to build the HTTP client: {:#}", e))?;
+                clients.insert(config.clone(), client.clone());
+                client
+            }
+        };
+        Ok(Self { config, client })
+    }
+}
+
+impl Serialize for HttpClient {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.config.serialize(serializer)
+    }
+}
+
+impl Deref for HttpClient {
+    type Target = reqwest::Client;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
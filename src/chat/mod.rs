@@ -2,35 +2,54 @@
 //!
 //! This module contains the chat router and the chat models.
 
+pub mod api_keys;
 pub mod chat_trait;
+pub mod circuit_breaker;
 pub mod errors;
+pub mod health;
+pub mod http;
 pub mod models;
+pub mod quota;
+pub mod rate_limit;
+pub mod registry;
+pub mod reload;
+pub mod replicas;
+pub mod retry;
 pub mod state;
+pub mod stream;
+use crate::auth::{self, Client};
 use crate::AppState;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 
 use axum::{
-    extract::{Json, State},
-    response::{IntoResponse, Response, Result},
+    extract::{Extension, Json, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response, Result,
+    },
     routing::{get, post},
     Router,
 };
-use std::{path::Path, sync::Arc};
+use std::{path::PathBuf, time::Duration};
 
-use self::state::ChatModels;
+use self::errors::ErrorResponse;
+use self::health::HealthStatus;
+use self::reload::ReloadableModels;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ModelsResponse {
     pub models: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct History {
     pub prompt: String,
     pub generation: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatRequest {
     pub uuid: String,
     pub model: String,
@@ -39,25 +58,117 @@ pub struct ChatRequest {
     pub prompt: String,
     #[serde(default = "Vec::new")]
     pub history: Vec<History>,
+    /// Who the request is made for, counted against the user and team quotas
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<String>,
     // For idempotency
 }
 
+/// Extra information some backends report about a generation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenerationDetails {
+    pub finish_reason: String,
+    pub generated_tokens: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChatResponse {
     pub generation: String,
     pub uuid: String,
+    /// Only present on fresh generations, the idempotency cache keeps just the text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<GenerationDetails>,
+    /// The model that generated the response, which is one of the chain's models when a
+    /// fallback chain was requested. Also only present on fresh generations.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatStreamToken {
+    pub token: String,
+}
+
+/// The longest `X-Request-Timeout` taken as is, longer ones are cut down to it
+const MAX_REQUEST_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Callers can bound a request with an `X-Request-Timeout` header, in seconds
+fn request_timeout(headers: &HeaderMap) -> std::result::Result<Option<Duration>, ErrorResponse> {
+    let Some(timeout) = headers.get("X-Request-Timeout") else {
+        return Ok(None);
+    };
+    match timeout
+        .to_str()
+        .ok()
+        .and_then(|timeout| timeout.trim().parse::<f64>().ok())
+        .and_then(|timeout| Duration::try_from_secs_f64(timeout).ok())
+    {
+        Some(timeout) => Ok(Some(timeout.min(MAX_REQUEST_TIMEOUT))),
+        None => Err(ErrorResponse::new(
+            "X-Request-Timeout must be a number of seconds",
+        )),
+    }
+}
+
+/// Refuse a model the authenticated client may not use
+fn forbidden(client: Option<Extension<Client>>, model: &str) -> Option<Response> {
+    let Extension(client) = client?;
+    if client.allows(model) {
+        return None;
+    }
+    tracing::warn!("Client {} may not use {}", client.name, model);
+    let error = ErrorResponse::new(format!("Not allowed to use model {}", model));
+    Some((StatusCode::FORBIDDEN, Json(error)).into_response())
+}
+
+/// Count the request against the authenticated client's quotas. A client is its own team, and
+/// its users are kept apart from other clients' users. Admin clients may act for any team.
+fn identify(client: &Option<Extension<Client>>, request: &mut ChatRequest) -> Option<Response> {
+    let Some(Extension(client)) = client else {
+        return None;
+    };
+    if client.admin {
+        request.team.get_or_insert_with(|| client.name.clone());
+        return None;
+    }
+    if let Some(team) = request.team.as_ref().filter(|team| **team != client.name) {
+        tracing::warn!("Client {} may not act for team {}", client.name, team);
+        let error = ErrorResponse::new(format!("Not allowed to act for team {}", team));
+        return Some((StatusCode::FORBIDDEN, Json(error)).into_response());
+    }
+    request.team = Some(client.name.clone());
+    request.user = request
+        .user
+        .take()
+        .map(|user| format!("{}/{}", client.name, user));
+    None
 }
 
 async fn chat(
     State(chat_state): State<ChatState>,
-    Json(request): Json<ChatRequest>,
+    client: Option<Extension<Client>>,
+    headers: HeaderMap,
+    Json(mut request): Json<ChatRequest>,
 ) -> Result<Response> {
     tracing::trace!("chat called");
+    if let Some(response) = identify(&client, &mut request) {
+        return Ok(response);
+    }
+    if let Some(response) = forbidden(client, &request.model) {
+        return Ok(response);
+    }
+    let deadline = match request_timeout(&headers) {
+        Ok(deadline) => deadline,
+        Err(error) => return Ok((StatusCode::BAD_REQUEST, Json(error)).into_response()),
+    };
     let redis_client = chat_state.app_state.redis_client.clone();
     let secret_manager = chat_state.app_state.secret_manager.clone();
     match chat_state
         .chat_models
-        .chat(redis_client, secret_manager, request)
+        .current()
+        .chat(redis_client, secret_manager, request, deadline)
         .await
     {
         Ok(generation) => Ok(Json(generation).into_response()),
@@ -65,32 +176,215 @@ async fn chat(
     }
 }
 
-async fn models(State(chat_state): State<ChatState>) -> Result<Response> {
+/// Streams the generation as server-sent events. Each token is sent as a `message` event,
+/// followed by a `done` event carrying the full `ChatResponse`, or an `error` event.
+async fn chat_stream(
+    State(chat_state): State<ChatState>,
+    client: Option<Extension<Client>>,
+    headers: HeaderMap,
+    Json(mut request): Json<ChatRequest>,
+) -> Result<Response> {
+    tracing::trace!("chat_stream called");
+    if let Some(response) = identify(&client, &mut request) {
+        return Ok(response);
+    }
+    if let Some(response) = forbidden(client, &request.model) {
+        return Ok(response);
+    }
+    let deadline = match request_timeout(&headers) {
+        Ok(deadline) => deadline,
+        Err(error) => return Ok((StatusCode::BAD_REQUEST, Json(error)).into_response()),
+    };
+    let redis_client = chat_state.app_state.redis_client.clone();
+    let secret_manager = chat_state.app_state.secret_manager.clone();
+    let uuid = request.uuid.clone();
+    let (model, tokens) = match chat_state
+        .chat_models
+        .current()
+        .chat_stream(redis_client, secret_manager, request, deadline)
+        .await
+    {
+        Ok(stream) => stream,
+        Err(e) => return Ok(e.into_response()),
+    };
+
+    let events = futures::stream::unfold(
+        Some((tokens, String::new())),
+        move |state| {
+            let uuid = uuid.clone();
+            let model = model.clone();
+            async move {
+                let (mut tokens, mut generation) = state?;
+                match tokens.next().await {
+                    Some(Ok(token)) => {
+                        generation.push_str(&token);
+                        let event = Event::default().json_data(ChatStreamToken { token });
+                        Some((event, Some((tokens, generation))))
+                    }
+                    Some(Err(e)) => {
+                        let event = Event::default()
+                            .event("error")
+                            .json_data(e.error_response());
+                        Some((event, None))
+                    }
+                    None => {
+                        let event = Event::default()
+                            .event("done")
+                            .json_data(ChatResponse {
+                                generation,
+                                uuid,
+                                details: None,
+                                model,
+                            });
+                        Some((event, None))
+                    }
+                }
+            }
+        },
+    );
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+/// Leave out the models the client may not use, and the unhealthy ones when they're hidden
+fn visible(chat_state: &ChatState, client: Option<&Client>, models: &mut Vec<String>) {
+    if let Some(client) = client {
+        models.retain(|model| client.allows(model));
+    }
+    if chat_state.health.hide_unhealthy {
+        models.retain(|model| chat_state.health.is_healthy(model));
+    }
+}
+
+async fn models(
+    State(chat_state): State<ChatState>,
+    client: Option<Extension<Client>>,
+) -> Result<Response> {
     tracing::trace!("models called");
-    let models = chat_state.chat_models.models().await?;
+    let mut models = chat_state.chat_models.current().models().await?;
+    visible(
+        &chat_state,
+        client.as_ref().map(|Extension(client)| client),
+        &mut models.models,
+    );
     Ok(Json(models).into_response())
 }
 
+/// Rebuilds the models from `MODEL_DIR/chat` and the quotas in `MODEL_DIR`. A broken config is
+/// rejected and the models already in service are kept. Only admin clients, or callers with the
+/// admin key when no clients are configured, may reload, and the reason a config was rejected is
+/// only logged. Responds with the models now listed on `/chat/models`.
+async fn reload(
+    State(chat_state): State<ChatState>,
+    client: Option<Extension<Client>>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    tracing::trace!("reload called");
+    match &client {
+        Some(Extension(client)) if !client.admin => {
+            tracing::warn!("Client {} may not reload the models", client.name);
+            let error = ErrorResponse::new("Not allowed to reload models");
+            return Ok((StatusCode::FORBIDDEN, Json(error)).into_response());
+        }
+        Some(_) => {}
+        // Without clients there is no middleware, so the admin key is checked here
+        None => {
+            if !auth::is_admin(&chat_state.app_state.secret_manager, &headers).await {
+                tracing::warn!("Rejected a reload without the admin key");
+                return Ok(auth::unauthorized("Reloading needs the admin key"));
+            }
+        }
+    }
+    match chat_state.chat_models.reload().await {
+        Ok(mut models) => {
+            visible(&chat_state, None, &mut models.models);
+            Ok(Json(models).into_response())
+        }
+        Err(e) => {
+            tracing::error!("Failed to reload models, keeping the current set: {:#}", e);
+            let error = ErrorResponse::new("Failed to reload models, keeping the current set");
+            Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(error)).into_response())
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ChatState {
-    pub chat_models: Arc<ChatModels>,
+    pub chat_models: ReloadableModels,
+    pub health: HealthStatus,
     pub app_state: AppState,
 }
 
-pub async fn chat_router(app_state: AppState) -> anyhow::Result<Router> {
-    let model_path = std::env::var("MODEL_DIR").unwrap_or_else(|_| "/opt/models/".to_string());
-    let chat_path = Path::new(model_path.as_str()).join("chat");
-    let chat_models = Arc::new(ChatModels::new(chat_path)?);
+/// Read a number of seconds from the environment
+fn seconds_from_env(name: &str) -> anyhow::Result<Option<Duration>> {
+    match std::env::var(name) {
+        Ok(seconds) => {
+            let seconds: u64 = seconds
+                .parse()
+                .map_err(|_| anyhow::anyhow!("{} must be a number of seconds", name))?;
+            Ok(Some(Duration::from_secs(seconds)))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Where the model config and the other config files are read from
+pub fn model_dir() -> PathBuf {
+    std::env::var("MODEL_DIR")
+        .unwrap_or_else(|_| "/opt/models/".to_string())
+        .into()
+}
+
+impl ChatState {
+    /// Load the models and start the background tasks that look after them
+    pub async fn load(app_state: AppState) -> anyhow::Result<Self> {
+        let require_secrets = std::env::var("MODEL_REQUIRE_SECRETS")
+            .map(|require| require == "true")
+            .unwrap_or(false);
+        let chat_models = ReloadableModels::load(
+            model_dir(),
+            app_state.secret_manager.clone(),
+            require_secrets,
+        )
+        .await?;
 
-    let chat_state = ChatState {
-        chat_models,
-        app_state,
-    };
-    let router = Router::new()
+        // Time between checks of the model config for changes, unset to only reload on request
+        if let Some(interval) = seconds_from_env("MODEL_RELOAD_INTERVAL")? {
+            tracing::info!("Watching model config every {:?}", interval);
+            chat_models.clone().watch(interval);
+        }
+
+        let hide_unhealthy = std::env::var("MODEL_HIDE_UNHEALTHY")
+            .map(|hide| hide == "true")
+            .unwrap_or(false);
+        let health = HealthStatus::new(hide_unhealthy);
+        // Time between health checks of the models, unset to not check them
+        if let Some(interval) = seconds_from_env("MODEL_HEALTH_INTERVAL")? {
+            tracing::info!("Checking model health every {:?}", interval);
+            health.probe(
+                chat_models.clone(),
+                app_state.secret_manager.clone(),
+                interval,
+            );
+        }
+
+        Ok(Self {
+            chat_models,
+            health,
+            app_state,
+        })
+    }
+}
+
+pub fn chat_router(chat_state: ChatState) -> Router {
+    Router::new()
         .route("/generate", post(chat))
         .with_state(chat_state.clone())
+        .route("/generate_stream", post(chat_stream))
+        .with_state(chat_state.clone())
         .route("/models", get(models))
-        .with_state(chat_state);
-
-    Ok(router)
+        .with_state(chat_state.clone())
+        .route("/reload", post(reload))
+        .with_state(chat_state)
 }
@@ -0,0 +1,310 @@
+use crate::{
+    chat::{
+        chat_trait::ChatLlm,
+        errors::ModelError,
+        http::HttpClient,
+        retry::{rate_limit_wait, DEFAULT_RATE_LIMIT_WAIT_MS},
+        stream::{sse_data, ChatStream},
+        History,
+    },
+    secret_manager::Secrets,
+};
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+const API_URL_V1: &str = "https://api.anthropic.com/v1";
+const API_VERSION: &str = "2023-06-01";
+const API_KEY_SECRET: &str = "ANTHROPIC_API_KEY";
+
+fn default_base_url() -> String {
+    API_URL_V1.to_string()
+}
+
+fn default_api_key_secret() -> String {
+    API_KEY_SECRET.to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AnthropicParameters {
+    pub max_tokens: u64,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<u64>,
+    pub stop_sequences: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[allow(non_camel_case_types)]
+pub enum AnthropicRole {
+    user,
+    assistant,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AnthropicMessage {
+    pub role: AnthropicRole,
+    pub content: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessagesRequest {
+    pub model: String,
+    pub messages: Vec<AnthropicMessage>,
+    pub max_tokens: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ContentBlock {
+    #[serde(rename = "type")]
+    pub block_type: String,
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessagesResponse {
+    pub content: Vec<ContentBlock>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamDelta {
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamError {
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub message: String,
+}
+
+/// A single server-sent event from a `stream: true` messages request
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+#[allow(non_camel_case_types)]
+pub enum StreamEvent {
+    content_block_delta { delta: StreamDelta },
+    error { error: StreamError },
+    #[serde(other)]
+    other,
+}
+
+impl StreamEvent {
+    /// Parse the `data:` payload of an event into its token, if it carries one
+    pub fn parse_token(data: &str) -> Option<Result<String, ModelError>> {
+        match serde_json::from_str::<StreamEvent>(data) {
+            Ok(StreamEvent::content_block_delta { delta }) => delta.text.map(Ok),
+            Ok(StreamEvent::error { error }) => {
+                tracing::error!(
+                    "Error in stream from anthropic: {}: {}",
+                    error.error_type,
+                    error.message
+                );
+                match error.error_type.as_str() {
+                    // The error event carries no retry delay, unlike a 429's Retry-After
+                    "rate_limit_error" => Some(Err(ModelError::RateLimitExceeded(
+                        DEFAULT_RATE_LIMIT_WAIT_MS,
+                    ))),
+                    _ => Some(Err(ModelError::UpstreamModelError)),
+                }
+            }
+            Ok(StreamEvent::other) => None,
+            Err(e) => {
+                tracing::error!("Error parsing stream event from anthropic: {}", e);
+                Some(Err(ModelError::UpstreamModelError))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AnthropicModel {
+    pub name: String,
+    pub model: String,
+    pub parameters: AnthropicParameters,
+    pub context_size: usize,
+    /// Root of the API, for a proxy or a gateway in front of it
+    #[serde(default = "default_base_url")]
+    pub base_url: String,
+    /// Name of the secret holding the API key
+    #[serde(default = "default_api_key_secret")]
+    pub api_key_secret: String,
+    #[serde(flatten)]
+    pub http: HttpClient,
+}
+
+impl AnthropicModel {
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url.trim_end_matches('/'), path)
+    }
+
+    fn request(
+        &self,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> MessagesRequest {
+        let mut messages = Vec::new();
+        for h in history {
+            messages.push(AnthropicMessage {
+                role: AnthropicRole::user,
+                content: h.prompt,
+            });
+            messages.push(AnthropicMessage {
+                role: AnthropicRole::assistant,
+                content: h.generation,
+            });
+        }
+        messages.push(AnthropicMessage {
+            role: AnthropicRole::user,
+            content: prompt,
+        });
+        MessagesRequest {
+            model: self.model.clone(),
+            messages,
+            max_tokens: self.parameters.max_tokens,
+            system,
+            temperature: self.parameters.temperature,
+            top_p: self.parameters.top_p,
+            top_k: self.parameters.top_k,
+            stop_sequences: self.parameters.stop_sequences.clone(),
+            stream: None,
+        }
+    }
+
+    async fn send(
+        &self,
+        secrets: Secrets,
+        request: &MessagesRequest,
+    ) -> Result<reqwest::Response, ModelError> {
+        let api_key = secrets
+            .get_secret(&self.api_key_secret)
+            .await
+            .ok_or(ModelError::Other("Missing Auth".to_string()))?;
+
+        let client = &self.http;
+        let response = client
+            .post(self.endpoint("messages"))
+            .json(request)
+            .header("x-api-key", api_key)
+            .header("anthropic-version", API_VERSION)
+            .send()
+            .await
+            .map_err(|e| {
+                tracing::error!("Error sending request to anthropic: {}", e);
+                ModelError::UpstreamModelError
+            })?;
+
+        match response.status() {
+            reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                tracing::error!("Rate limit exceeded");
+                Err(ModelError::RateLimitExceeded(rate_limit_wait(&response)))
+            }
+            status if status.is_client_error() || status.is_server_error() => {
+                // 529 is Anthropic's "overloaded" status
+                tracing::error!(
+                    "Error from anthropic ({}): {}",
+                    status,
+                    response
+                        .text()
+                        .await
+                        .unwrap_or_else(|_| "Unknown".to_string())
+                );
+                Err(ModelError::UpstreamModelError)
+            }
+            _ => Ok(response),
+        }
+    }
+}
+
+#[async_trait]
+impl ChatLlm for AnthropicModel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn context_size(&self) -> usize {
+        self.context_size
+    }
+
+    fn secrets(&self) -> Vec<&str> {
+        vec![&self.api_key_secret]
+    }
+
+    /// Looks the model up, which checks the key without spending tokens
+    async fn health_check(&self, secrets: Secrets) -> Result<(), ModelError> {
+        let api_key = secrets
+            .get_secret(&self.api_key_secret)
+            .await
+            .ok_or(ModelError::Other("Missing Auth".to_string()))?;
+        self.http
+            .get(self.endpoint(&format!("models/{}", self.model)))
+            .header("x-api-key", api_key)
+            .header("anthropic-version", API_VERSION)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| ModelError::Other(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn chat(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<String, ModelError> {
+        let request = self.request(prompt, system, history);
+        let response = self.send(secrets, &request).await?;
+
+        let response: MessagesResponse = response.json().await.map_err(|e| {
+            tracing::error!("Error parsing response from anthropic: {}", e);
+            ModelError::UpstreamModelError
+        })?;
+
+        let generation: String = response
+            .content
+            .into_iter()
+            .filter(|block| block.block_type == "text")
+            .filter_map(|block| block.text)
+            .collect();
+        if generation.is_empty() {
+            tracing::error!("No generation in response from anthropic");
+            return Err(ModelError::UpstreamModelError);
+        }
+        Ok(generation)
+    }
+
+    async fn chat_stream(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<ChatStream, ModelError> {
+        let mut request = self.request(prompt, system, history);
+        request.stream = Some(true);
+        let response = self.send(secrets, &request).await?;
+
+        let tokens = sse_data(response).filter_map(|data| async move {
+            match data {
+                Ok(data) => StreamEvent::parse_token(&data),
+                Err(e) => Some(Err(e)),
+            }
+        });
+        Ok(Box::pin(tokens))
+    }
+}
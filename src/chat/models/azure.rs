@@ -0,0 +1,192 @@
+use super::openai::{
+    ChatCompletionChunk, ChatCompletionRequest, ChatCompletionResponse, OpenAIParameters,
+};
+use crate::{
+    chat::{
+        chat_trait::ChatLlm,
+        errors::ModelError,
+        http::HttpClient,
+        retry::rate_limit_wait,
+        stream::{sse_data, ChatStream},
+        History,
+    },
+    secret_manager::Secrets,
+};
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+const API_KEY_SECRET: &str = "AZURE_OPENAI_API_KEY";
+
+fn default_api_key_secret() -> String {
+    API_KEY_SECRET.to_string()
+}
+
+#[derive(Debug, Deserialize)]
+struct AzureErrorBody {
+    code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AzureError {
+    error: AzureErrorBody,
+}
+
+/// An OpenAI model deployed through Azure OpenAI
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AzureOpenAIModel {
+    pub name: String,
+    /// The resource endpoint, e.g. `https://my-resource.openai.azure.com`
+    pub endpoint: String,
+    pub deployment: String,
+    pub api_version: String,
+    pub parameters: OpenAIParameters,
+    pub context_size: usize,
+    /// Name of the secret holding the `api-key`
+    #[serde(default = "default_api_key_secret")]
+    pub api_key_secret: String,
+    #[serde(flatten)]
+    pub http: HttpClient,
+}
+
+impl AzureOpenAIModel {
+    async fn send(
+        &self,
+        secrets: Secrets,
+        request: &ChatCompletionRequest,
+    ) -> Result<reqwest::Response, ModelError> {
+        let api_key = secrets
+            .get_secret(&self.api_key_secret)
+            .await
+            .ok_or(ModelError::Other("Missing Auth".to_string()))?;
+
+        let client = &self.http;
+        let response = client
+            .post(format!(
+                "{}/openai/deployments/{}/chat/completions",
+                self.endpoint.trim_end_matches('/'),
+                self.deployment
+            ))
+            .query(&[("api-version", &self.api_version)])
+            .json(request)
+            .header("api-key", api_key)
+            .send()
+            .await
+            .map_err(|e| {
+                tracing::error!("Error sending request to azure: {}", e);
+                ModelError::UpstreamModelError
+            })?;
+
+        match response.status() {
+            reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                tracing::error!("Rate limit exceeded");
+                Err(ModelError::RateLimitExceeded(rate_limit_wait(&response)))
+            }
+            status if status.is_client_error() || status.is_server_error() => {
+                let body = response
+                    .text()
+                    .await
+                    .unwrap_or_else(|_| "Unknown".to_string());
+                // Prompts caught by Azure's content filter come back as a 400
+                let code = serde_json::from_str::<AzureError>(&body)
+                    .ok()
+                    .and_then(|error| error.error.code);
+                if code.as_deref() == Some("content_filter") {
+                    tracing::error!("Prompt blocked by the content filter: {}", body);
+                    return Err(ModelError::ContentFiltered);
+                }
+                tracing::error!("Error from azure: {}", body);
+                Err(ModelError::UpstreamModelError)
+            }
+            _ => Ok(response),
+        }
+    }
+}
+
+#[async_trait]
+impl ChatLlm for AzureOpenAIModel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn context_size(&self) -> usize {
+        self.context_size
+    }
+
+    fn secrets(&self) -> Vec<&str> {
+        vec![&self.api_key_secret]
+    }
+
+    /// Lists the resource's models, which checks the endpoint and key without spending tokens
+    async fn health_check(&self, secrets: Secrets) -> Result<(), ModelError> {
+        let api_key = secrets
+            .get_secret(&self.api_key_secret)
+            .await
+            .ok_or(ModelError::Other("Missing Auth".to_string()))?;
+        self.http
+            .get(format!(
+                "{}/openai/models",
+                self.endpoint.trim_end_matches('/')
+            ))
+            .query(&[("api-version", &self.api_version)])
+            .header("api-key", api_key)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| ModelError::Other(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn chat(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<String, ModelError> {
+        let request = ChatCompletionRequest::new(
+            self.deployment.clone(),
+            &self.parameters,
+            prompt,
+            system,
+            history,
+        );
+        let response = self.send(secrets, &request).await?;
+
+        let response: ChatCompletionResponse = response.json().await.map_err(|e| {
+            tracing::error!("Error parsing response from azure: {}", e);
+            ModelError::UpstreamModelError
+        })?;
+        response.into_generation()
+    }
+
+    async fn chat_stream(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<ChatStream, ModelError> {
+        let mut request = ChatCompletionRequest::new(
+            self.deployment.clone(),
+            &self.parameters,
+            prompt,
+            system,
+            history,
+        );
+        request.stream = Some(true);
+        let response = self.send(secrets, &request).await?;
+
+        let tokens = sse_data(response)
+            .take_while(|data| {
+                futures::future::ready(!matches!(data, Ok(data) if data == "[DONE]"))
+            })
+            .filter_map(|data| async move {
+                match data {
+                    Ok(data) => ChatCompletionChunk::parse_token(&data),
+                    Err(e) => Some(Err(e)),
+                }
+            });
+        Ok(Box::pin(tokens))
+    }
+}
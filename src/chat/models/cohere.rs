@@ -0,0 +1,251 @@
+use crate::{
+    chat::{
+        chat_trait::ChatLlm,
+        errors::ModelError,
+        http::HttpClient,
+        retry::rate_limit_wait,
+        stream::{lines, ChatStream},
+        History,
+    },
+    secret_manager::Secrets,
+};
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+const API_URL_V1: &str = "https://api.cohere.ai/v1";
+const API_KEY_SECRET: &str = "COHERE_API_KEY";
+
+fn default_base_url() -> String {
+    API_URL_V1.to_string()
+}
+
+fn default_api_key_secret() -> String {
+    API_KEY_SECRET.to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct CohereParameters {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum CohereRole {
+    USER,
+    CHATBOT,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CohereMessage {
+    pub role: CohereRole,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CohereChatRequest<'a> {
+    pub model: &'a str,
+    pub message: String,
+    pub chat_history: Vec<CohereMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preamble: Option<String>,
+    #[serde(flatten)]
+    pub parameters: &'a CohereParameters,
+    pub stream: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CohereChatResponse {
+    pub text: String,
+}
+
+/// One line of a streamed response
+#[derive(Debug, Deserialize)]
+pub struct CohereStreamEvent {
+    pub event_type: String,
+    pub text: Option<String>,
+    pub finish_reason: Option<String>,
+}
+
+impl CohereStreamEvent {
+    /// Parse one line of a streamed response into its token, if it carries one
+    pub fn parse_token(line: &str) -> Option<Result<String, ModelError>> {
+        if line.is_empty() {
+            return None;
+        }
+        match serde_json::from_str::<CohereStreamEvent>(line) {
+            Ok(event) => match event.event_type.as_str() {
+                "text-generation" => event.text.map(Ok),
+                "stream-end" if event.finish_reason.as_deref() == Some("ERROR") => {
+                    tracing::error!("Error in stream from cohere");
+                    Some(Err(ModelError::UpstreamModelError))
+                }
+                _ => None,
+            },
+            Err(e) => {
+                tracing::error!("Error parsing stream line from cohere: {}", e);
+                Some(Err(ModelError::UpstreamModelError))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CohereModel {
+    pub name: String,
+    pub model: String,
+    #[serde(default)]
+    pub parameters: CohereParameters,
+    pub context_size: usize,
+    #[serde(default = "default_base_url")]
+    pub base_url: String,
+    /// Name of the secret holding the API key
+    #[serde(default = "default_api_key_secret")]
+    pub api_key_secret: String,
+    #[serde(flatten)]
+    pub http: HttpClient,
+}
+
+impl CohereModel {
+    async fn send(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+        stream: bool,
+    ) -> Result<reqwest::Response, ModelError> {
+        let mut chat_history = Vec::new();
+        for h in history {
+            chat_history.push(CohereMessage {
+                role: CohereRole::USER,
+                message: h.prompt,
+            });
+            chat_history.push(CohereMessage {
+                role: CohereRole::CHATBOT,
+                message: h.generation,
+            });
+        }
+        let request = CohereChatRequest {
+            model: &self.model,
+            message: prompt,
+            chat_history,
+            preamble: system,
+            parameters: &self.parameters,
+            stream,
+        };
+
+        let api_key = secrets
+            .get_secret(&self.api_key_secret)
+            .await
+            .ok_or(ModelError::Other("Missing Auth".to_string()))?;
+
+        let client = &self.http;
+        let response = client
+            .post(format!("{}/chat", self.base_url.trim_end_matches('/')))
+            .json(&request)
+            .header("Authorization", format!("Bearer {}", api_key))
+            .send()
+            .await
+            .map_err(|e| {
+                tracing::error!("Error sending request to cohere: {}", e);
+                ModelError::UpstreamModelError
+            })?;
+
+        match response.status() {
+            reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                tracing::error!("Rate limit exceeded");
+                Err(ModelError::RateLimitExceeded(rate_limit_wait(&response)))
+            }
+            status if status.is_client_error() || status.is_server_error() => {
+                tracing::error!(
+                    "Error from cohere: {}",
+                    response
+                        .text()
+                        .await
+                        .unwrap_or_else(|_| "Unknown".to_string())
+                );
+                Err(ModelError::UpstreamModelError)
+            }
+            _ => Ok(response),
+        }
+    }
+}
+
+#[async_trait]
+impl ChatLlm for CohereModel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn context_size(&self) -> usize {
+        self.context_size
+    }
+
+    fn secrets(&self) -> Vec<&str> {
+        vec![&self.api_key_secret]
+    }
+
+    /// Looks the model up, which checks the key without spending tokens
+    async fn health_check(&self, secrets: Secrets) -> Result<(), ModelError> {
+        let api_key = secrets
+            .get_secret(&self.api_key_secret)
+            .await
+            .ok_or(ModelError::Other("Missing Auth".to_string()))?;
+        self.http
+            .get(format!(
+                "{}/models/{}",
+                self.base_url.trim_end_matches('/'),
+                self.model
+            ))
+            .header("Authorization", format!("Bearer {}", api_key))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| ModelError::Other(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn chat(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<String, ModelError> {
+        let response = self.send(secrets, prompt, system, history, false).await?;
+
+        let response: CohereChatResponse = response.json().await.map_err(|e| {
+            tracing::error!("Error parsing response from cohere: {}", e);
+            ModelError::UpstreamModelError
+        })?;
+        Ok(response.text)
+    }
+
+    async fn chat_stream(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<ChatStream, ModelError> {
+        let response = self.send(secrets, prompt, system, history, true).await?;
+
+        let tokens = lines(response.bytes_stream()).filter_map(|line| async move {
+            match line {
+                Ok(line) => CohereStreamEvent::parse_token(&line),
+                Err(e) => Some(Err(e)),
+            }
+        });
+        Ok(Box::pin(tokens))
+    }
+}
@@ -0,0 +1,291 @@
+use crate::{
+    chat::{
+        chat_trait::ChatLlm,
+        errors::ModelError,
+        http::HttpClient,
+        retry::rate_limit_wait,
+        stream::{sse_data, ChatStream},
+        History,
+    },
+    secret_manager::Secrets,
+};
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+const API_URL_V1BETA: &str = "https://generativelanguage.googleapis.com/v1beta";
+const API_KEY_SECRET: &str = "GEMINI_API_KEY";
+
+fn default_base_url() -> String {
+    API_URL_V1BETA.to_string()
+}
+
+fn default_api_key_secret() -> String {
+    API_KEY_SECRET.to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiGenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Part {
+    #[serde(default)]
+    pub text: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[allow(non_camel_case_types)]
+pub enum GeminiRole {
+    user,
+    model,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Content {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<GeminiRole>,
+    #[serde(default)]
+    pub parts: Vec<Part>,
+}
+
+impl Content {
+    fn text(role: Option<GeminiRole>, text: String) -> Self {
+        Self {
+            role,
+            parts: vec![Part { text }],
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateContentRequest<'a> {
+    pub contents: Vec<Content>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_instruction: Option<Content>,
+    pub generation_config: &'a GeminiGenerationConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Candidate {
+    pub content: Option<Content>,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PromptFeedback {
+    pub block_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateContentResponse {
+    #[serde(default)]
+    pub candidates: Vec<Candidate>,
+    pub prompt_feedback: Option<PromptFeedback>,
+}
+
+impl GenerateContentResponse {
+    /// The text of the first candidate, or why it was blocked. Streamed chunks may carry no text.
+    fn into_text(mut self) -> Result<String, ModelError> {
+        if let Some(reason) = self.prompt_feedback.and_then(|f| f.block_reason) {
+            tracing::error!("Prompt blocked by gemini: {}", reason);
+            return Err(ModelError::ContentFiltered);
+        }
+        if self.candidates.is_empty() {
+            return Ok(String::new());
+        }
+        let candidate = self.candidates.swap_remove(0);
+        if let Some(reason) = candidate.finish_reason.as_deref() {
+            if matches!(
+                reason,
+                "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII"
+            ) {
+                tracing::error!("Generation blocked by gemini: {}", reason);
+                return Err(ModelError::ContentFiltered);
+            }
+        }
+        Ok(candidate
+            .content
+            .map(|content| content.parts.into_iter().map(|part| part.text).collect())
+            .unwrap_or_default())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GeminiModel {
+    pub name: String,
+    /// The model id, e.g. `gemini-1.5-flash`
+    pub model: String,
+    #[serde(default)]
+    pub generation_config: GeminiGenerationConfig,
+    pub context_size: usize,
+    /// Root of the API, for a proxy or a regional endpoint in front of it
+    #[serde(default = "default_base_url")]
+    pub base_url: String,
+    /// Name of the secret holding the API key
+    #[serde(default = "default_api_key_secret")]
+    pub api_key_secret: String,
+    #[serde(flatten)]
+    pub http: HttpClient,
+}
+
+impl GeminiModel {
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}/models/{}", self.base_url.trim_end_matches('/'), path)
+    }
+
+    async fn send(
+        &self,
+        secrets: Secrets,
+        method: &str,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<reqwest::Response, ModelError> {
+        let mut contents = Vec::new();
+        for h in history {
+            contents.push(Content::text(Some(GeminiRole::user), h.prompt));
+            contents.push(Content::text(Some(GeminiRole::model), h.generation));
+        }
+        contents.push(Content::text(Some(GeminiRole::user), prompt));
+        let request = GenerateContentRequest {
+            contents,
+            system_instruction: system.map(|system| Content::text(None, system)),
+            generation_config: &self.generation_config,
+        };
+
+        let api_key = secrets
+            .get_secret(&self.api_key_secret)
+            .await
+            .ok_or(ModelError::Other("Missing Auth".to_string()))?;
+
+        let client = &self.http;
+        let response = client
+            .post(self.endpoint(&format!("{}:{}", self.model, method)))
+            .json(&request)
+            .header("x-goog-api-key", api_key)
+            .send()
+            .await
+            .map_err(|e| {
+                tracing::error!("Error sending request to gemini: {}", e);
+                ModelError::UpstreamModelError
+            })?;
+
+        match response.status() {
+            reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                tracing::error!("Rate limit exceeded");
+                Err(ModelError::RateLimitExceeded(rate_limit_wait(&response)))
+            }
+            status if status.is_client_error() || status.is_server_error() => {
+                tracing::error!(
+                    "Error from gemini: {}",
+                    response
+                        .text()
+                        .await
+                        .unwrap_or_else(|_| "Unknown".to_string())
+                );
+                Err(ModelError::UpstreamModelError)
+            }
+            _ => Ok(response),
+        }
+    }
+}
+
+#[async_trait]
+impl ChatLlm for GeminiModel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn context_size(&self) -> usize {
+        self.context_size
+    }
+
+    fn secrets(&self) -> Vec<&str> {
+        vec![&self.api_key_secret]
+    }
+
+    /// Looks the model up, which checks the key without spending tokens
+    async fn health_check(&self, secrets: Secrets) -> Result<(), ModelError> {
+        let api_key = secrets
+            .get_secret(&self.api_key_secret)
+            .await
+            .ok_or(ModelError::Other("Missing Auth".to_string()))?;
+        self.http
+            .get(self.endpoint(&self.model))
+            .header("x-goog-api-key", api_key)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| ModelError::Other(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn chat(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<String, ModelError> {
+        let response = self
+            .send(secrets, "generateContent", prompt, system, history)
+            .await?;
+
+        let response: GenerateContentResponse = response.json().await.map_err(|e| {
+            tracing::error!("Error parsing response from gemini: {}", e);
+            ModelError::UpstreamModelError
+        })?;
+        let generation = response.into_text()?;
+        if generation.is_empty() {
+            tracing::error!("No generation in response from gemini");
+            return Err(ModelError::UpstreamModelError);
+        }
+        Ok(generation)
+    }
+
+    async fn chat_stream(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<ChatStream, ModelError> {
+        let response = self
+            .send(secrets, "streamGenerateContent?alt=sse", prompt, system, history)
+            .await?;
+
+        let tokens = sse_data(response).filter_map(|data| async move {
+            let data = match data {
+                Ok(data) => data,
+                Err(e) => return Some(Err(e)),
+            };
+            match serde_json::from_str::<GenerateContentResponse>(&data) {
+                Ok(response) => match response.into_text() {
+                    Ok(text) if text.is_empty() => None,
+                    text => Some(text),
+                },
+                Err(e) => {
+                    tracing::error!("Error parsing stream chunk from gemini: {}", e);
+                    Some(Err(ModelError::UpstreamModelError))
+                }
+            }
+        });
+        Ok(Box::pin(tokens))
+    }
+}
@@ -1,14 +1,27 @@
 use crate::{
-    chat::{chat_trait::ChatLlm, errors::ModelError, History},
+    chat::{
+        api_keys::ApiKeys,
+        chat_trait::ChatLlm,
+        errors::ModelError,
+        http::HttpClient,
+        replicas::{ReplicaGuard, Replicas},
+        retry::{rate_limit_wait, RetryPolicy},
+        stream::{sse_data, ChatStream},
+        History,
+    },
     secret_manager::Secrets,
 };
 use async_trait::async_trait;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::path::Path;
 use tracing;
 
-use reqwest::Client;
+const API_KEY_SECRET: &str = "HUGGINGFACE_API_TOKEN";
+
+fn default_api_key_secret() -> String {
+    API_KEY_SECRET.to_string()
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HuggingFaceModelParameters {
@@ -38,31 +51,31 @@ pub struct HuggingFacePromptFormat {
 impl HuggingFacePromptFormat {
     pub fn format_system_prompt(&self, system: &str) -> String {
         if self.close_system_token.is_empty() {
-            return format!("{}{}{}", self.system_token, system, self.stop_token);
+            format!("{}{}{}", self.system_token, system, self.stop_token)
         } else {
-            return format!(
+            format!(
                 "{}{}{}",
                 self.system_token, system, self.close_system_token
-            );
+            )
         }
     }
 
     pub fn format_prompt(&self, prompt: &str) -> String {
         if self.close_prompt_token.is_empty() {
-            return format!("{}{}{}", self.prompt_token, prompt, self.stop_token);
+            format!("{}{}{}", self.prompt_token, prompt, self.stop_token)
         } else {
-            return format!("{}{}{}", self.prompt_token, prompt, self.close_prompt_token);
+            format!("{}{}{}", self.prompt_token, prompt, self.close_prompt_token)
         }
     }
 
     pub fn format_assistant_prompt(&self, assistant: &str) -> String {
         if self.close_assistant_token.is_empty() {
-            return format!("{}{}{}", self.assistant_token, assistant, self.stop_token);
+            format!("{}{}{}", self.assistant_token, assistant, self.stop_token)
         } else {
-            return format!(
+            format!(
                 "{}{}{}",
                 self.assistant_token, assistant, self.close_assistant_token
-            );
+            )
         }
     }
 
@@ -84,10 +97,24 @@ impl HuggingFacePromptFormat {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HuggingFaceModel {
     pub name: String,
-    pub url: String,
+    /// A single `url`, or several `replicas` to balance requests across
+    #[serde(flatten)]
+    pub replicas: Replicas,
     pub parameters: HuggingFaceModelParameters,
     pub prompt_format: HuggingFacePromptFormat,
     pub context_size: usize,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    /// Name of the secret holding the API key
+    #[serde(default = "default_api_key_secret")]
+    pub api_key_secret: String,
+    /// Names of several secrets holding API keys, used in turn in place of `api_key_secret`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub api_key_secrets: Vec<String>,
+    #[serde(skip)]
+    pub keys: ApiKeys,
+    #[serde(flatten)]
+    pub http: HttpClient,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -97,48 +124,96 @@ struct Generation {
 
 type HuggingFaceResponse = Vec<Generation>;
 
-#[async_trait]
-impl ChatLlm for HuggingFaceModel {
-    fn name(&self) -> &str {
-        &self.name
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StreamToken {
+    pub text: String,
+    #[serde(default)]
+    pub special: bool,
+}
+
+/// A single server-sent event from text-generation-inference's streaming endpoint
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StreamResponse {
+    pub token: Option<StreamToken>,
+    pub error: Option<String>,
+}
+
+impl StreamResponse {
+    /// Parse the `data:` payload of an event into its token, if it carries one
+    pub fn parse_token(data: &str) -> Option<Result<String, ModelError>> {
+        match serde_json::from_str::<StreamResponse>(data) {
+            Ok(StreamResponse {
+                error: Some(error), ..
+            }) => {
+                tracing::error!("Error in stream from huggingface: {}", error);
+                Some(Err(ModelError::UpstreamModelError))
+            }
+            Ok(StreamResponse {
+                token: Some(token), ..
+            }) if !token.special => Some(Ok(token.text)),
+            Ok(_) => None,
+            Err(e) => {
+                tracing::error!("Error parsing stream event from huggingface: {}", e);
+                Some(Err(ModelError::UpstreamModelError))
+            }
+        }
     }
+}
 
-    fn context_size(&self) -> usize {
-        self.context_size
+impl HuggingFaceModel {
+    /// The secrets holding the API keys
+    fn key_secrets(&self) -> Vec<&str> {
+        if self.api_key_secrets.is_empty() {
+            vec![&self.api_key_secret]
+        } else {
+            self.api_key_secrets.iter().map(String::as_str).collect()
+        }
     }
 
-    async fn chat(
+    async fn send(
         &self,
         secrets: Secrets,
         prompt: String,
         system: Option<String>,
         history: Vec<History>,
-    ) -> Result<String, ModelError> {
+        stream: bool,
+    ) -> Result<(reqwest::Response, ReplicaGuard), ModelError> {
         let full_prompt = self.prompt_format.format(
             system.as_deref(),
             &prompt,
             &history,
         );
 
-        let auth_token = secrets
-            .get_secret("HUGGINGFACE_API_TOKEN")
-            .await
-            .ok_or(ModelError::Other("Missing Auth".to_string()))?;
-
-        let client = Client::new();
-        let response = client
-            .post(&self.url)
-            .json(&serde_json::json!({
-                "inputs": full_prompt,
-                "parameters": self.parameters,
-                "stream": false,
-            }))
-            .header("Authorization", format!("Bearer {}", auth_token))
-            .send()
+        let keys = self
+            .keys
+            .lookup(&self.name, &secrets, &self.key_secrets())
+            .await?;
+
+        let body = serde_json::json!({
+            "inputs": full_prompt,
+            "parameters": self.parameters,
+            "stream": stream,
+        });
+        let client = &self.http;
+        let (response, replica) = self
+            .retry
+            .send(|| {
+                // A rate limited key moves on to the next one before the attempt counts
+                self.keys.send(&self.name, &keys, |auth_token| {
+                    let authorization = format!("Bearer {}", auth_token.unwrap_or_default());
+                    // Each attempt picks a replica again, so a retry can go to a healthy one
+                    self.replicas.send(|url| {
+                        client
+                            .post(url)
+                            .json(&body)
+                            .header("Authorization", authorization)
+                    })
+                })
+            })
             .await
             .map_err(|e| {
                 tracing::error!("Error sending request to huggingface: {}", e);
-                ModelError::UpstreamModelError
+                ModelError::from(e)
             })?;
 
         if response.status().is_server_error() {
@@ -156,7 +231,7 @@ impl ChatLlm for HuggingFaceModel {
             match response.status() {
                 reqwest::StatusCode::TOO_MANY_REQUESTS => {
                     tracing::error!("Rate limit exceeded");
-                    return Err(ModelError::RateLimitExceeded(1000));
+                    return Err(ModelError::RateLimitExceeded(rate_limit_wait(&response)));
                 }
                 _ => {
                     tracing::error!(
@@ -170,6 +245,71 @@ impl ChatLlm for HuggingFaceModel {
                 }
             }
         }
+        Ok((response, replica))
+    }
+}
+
+#[async_trait]
+impl ChatLlm for HuggingFaceModel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn context_size(&self) -> usize {
+        self.context_size
+    }
+
+    fn secrets(&self) -> Vec<&str> {
+        self.key_secrets()
+    }
+
+    /// Healthy while a replica answers a `GET` without a server error or refusing the key. The
+    /// hosted API and inference endpoints answer it differently, but neither generates anything.
+    async fn health_check(&self, secrets: Secrets) -> Result<(), ModelError> {
+        let keys = self
+            .keys
+            .lookup(&self.name, &secrets, &self.key_secrets())
+            .await?;
+        let authorization = format!("Bearer {}", keys.first().unwrap_or_default());
+        let checks = self.replicas.urls().map(|url| {
+            let authorization = authorization.clone();
+            async move {
+                let response = self
+                    .http
+                    .get(url)
+                    .header("Authorization", authorization)
+                    .send()
+                    .await
+                    .map_err(|e| format!("{}: {}", url, e))?;
+                let status = response.status();
+                if status.is_server_error()
+                    || status == reqwest::StatusCode::UNAUTHORIZED
+                    || status == reqwest::StatusCode::FORBIDDEN
+                {
+                    return Err(format!("{}: {}", url, status));
+                }
+                Ok(())
+            }
+        });
+        let (healthy, errors): (Vec<_>, Vec<_>) = futures::future::join_all(checks)
+            .await
+            .into_iter()
+            .partition(Result::is_ok);
+        if healthy.is_empty() {
+            let errors: Vec<String> = errors.into_iter().filter_map(Result::err).collect();
+            return Err(ModelError::Other(errors.join(", ")));
+        }
+        Ok(())
+    }
+
+    async fn chat(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<String, ModelError> {
+        let (response, _replica) = self.send(secrets, prompt, system, history, false).await?;
 
         let mut response: HuggingFaceResponse = response.json().await.map_err(|e| {
             tracing::error!("Error parsing response from huggingface: {}", e);
@@ -181,17 +321,22 @@ impl ChatLlm for HuggingFaceModel {
         })?;
         Ok(generation.generated_text)
     }
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct HuggingFaceModels {
-    pub models: Vec<HuggingFaceModel>,
-}
+    async fn chat_stream(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<ChatStream, ModelError> {
+        let (response, replica) = self.send(secrets, prompt, system, history, true).await?;
 
-impl HuggingFaceModels {
-    pub fn new<P: AsRef<Path> + Send + Sync>(file: P) -> anyhow::Result<Self> {
-        let file = std::fs::File::open(file)?;
-        let models: Vec<HuggingFaceModel> = serde_json::from_reader(file)?;
-        Ok(Self { models })
+        let tokens = sse_data(response).filter_map(|data| async move {
+            match data {
+                Ok(data) => StreamResponse::parse_token(&data),
+                Err(e) => Some(Err(e)),
+            }
+        });
+        Ok(replica.hold(Box::pin(tokens)))
     }
 }
@@ -4,9 +4,6 @@ use crate::{
 };
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use serde_json;
-use std::collections::HashMap;
-use std::path::Path;
 use tracing;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -50,10 +47,19 @@ impl ChatLlm for MockModel {
                 tracing::info!("Mocking system too long");
                 Err(ModelError::SystemTooLong)
             }
+            "content_filtered" => {
+                tracing::info!("Mocking content filter");
+                Err(ModelError::ContentFiltered)
+            }
             "error" => {
                 tracing::info!("Mocking other error");
                 Err(ModelError::Other("Other error".to_string()))
             }
+            "slow" => {
+                tracing::info!("Mocking slow response");
+                tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+                Ok(format!("response: {}, {}", history.len(),self.short.clone()))
+            }
             "long_response" => {
                 tracing::info!("Mocking long response");
                 Ok(format!("response: {}, {}", history.len(),self.long.clone()))
@@ -65,16 +71,3 @@ impl ChatLlm for MockModel {
         }
     }
 }
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct MockModels {
-    pub models: HashMap<String, MockModel>,
-}
-
-impl MockModels {
-    pub fn new<P: AsRef<Path> + Send + Sync>(file: P) -> Self {
-        let contents = std::fs::read_to_string(file).unwrap();
-        let models: HashMap<String, MockModel> = serde_json::from_str(&contents).unwrap();
-        Self { models }
-    }
-}
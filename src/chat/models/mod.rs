@@ -1,9 +1,21 @@
+mod anthropic;
+mod azure;
+mod cohere;
+mod gemini;
 mod huggingface;
 mod reflection;
 mod mock;
+mod ollama;
 mod openai;
+mod tgi;
 
-pub use huggingface::HuggingFaceModels;
-pub use reflection::ReflectionModels;
-pub use mock::MockModels;
-pub use openai::OpenAIModels;
+pub use anthropic::AnthropicModel;
+pub use azure::AzureOpenAIModel;
+pub use cohere::CohereModel;
+pub use gemini::GeminiModel;
+pub use huggingface::HuggingFaceModel;
+pub use reflection::ReflectionModel;
+pub use mock::MockModel;
+pub use ollama::OllamaModel;
+pub use openai::OpenAIModel;
+pub use tgi::TgiModel;
\ No newline at end of file
@@ -0,0 +1,257 @@
+use crate::{
+    chat::{
+        chat_trait::ChatLlm,
+        errors::ModelError,
+        http::HttpClient,
+        retry::rate_limit_wait,
+        stream::{lines, ChatStream},
+        History,
+    },
+    secret_manager::Secrets,
+};
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_URL: &str = "http://localhost:11434";
+
+fn default_url() -> String {
+    DEFAULT_URL.to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_ctx: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[allow(non_camel_case_types)]
+pub enum OllamaRole {
+    system,
+    user,
+    assistant,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OllamaMessage {
+    pub role: OllamaRole,
+    pub content: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OllamaChatRequest<'a> {
+    pub model: &'a str,
+    pub messages: Vec<OllamaMessage>,
+    pub options: &'a OllamaOptions,
+    pub stream: bool,
+}
+
+/// The whole response, or one line of a streamed one
+#[derive(Debug, Deserialize)]
+pub struct OllamaChatResponse {
+    pub message: Option<OllamaMessage>,
+    pub error: Option<String>,
+}
+
+impl OllamaChatResponse {
+    /// Parse one line of a streamed response into its token, if it carries one
+    pub fn parse_token(line: &str) -> Option<Result<String, ModelError>> {
+        if line.is_empty() {
+            return None;
+        }
+        match serde_json::from_str::<OllamaChatResponse>(line) {
+            Ok(OllamaChatResponse {
+                error: Some(error), ..
+            }) => {
+                tracing::error!("Error in stream from ollama: {}", error);
+                Some(Err(ModelError::UpstreamModelError))
+            }
+            Ok(OllamaChatResponse {
+                message: Some(message),
+                ..
+            }) if !message.content.is_empty() => Some(Ok(message.content)),
+            Ok(_) => None,
+            Err(e) => {
+                tracing::error!("Error parsing stream line from ollama: {}", e);
+                Some(Err(ModelError::UpstreamModelError))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OllamaModel {
+    pub name: String,
+    /// The model tag as Ollama knows it, e.g. `llama3:8b`
+    pub model: String,
+    #[serde(default = "default_url")]
+    pub url: String,
+    #[serde(default)]
+    pub options: OllamaOptions,
+    /// Defaults to `options.num_ctx`
+    pub context_size: Option<usize>,
+    #[serde(flatten)]
+    pub http: HttpClient,
+}
+
+/// The models an Ollama server has pulled, from `/api/tags`
+#[derive(Debug, Deserialize)]
+pub struct OllamaTags {
+    pub models: Vec<OllamaTag>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OllamaTag {
+    pub name: String,
+}
+
+impl OllamaModel {
+    async fn send(
+        &self,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+        stream: bool,
+    ) -> Result<reqwest::Response, ModelError> {
+        let mut messages = Vec::new();
+        if let Some(system) = system {
+            messages.push(OllamaMessage {
+                role: OllamaRole::system,
+                content: system,
+            });
+        }
+        for h in history {
+            messages.push(OllamaMessage {
+                role: OllamaRole::user,
+                content: h.prompt,
+            });
+            messages.push(OllamaMessage {
+                role: OllamaRole::assistant,
+                content: h.generation,
+            });
+        }
+        messages.push(OllamaMessage {
+            role: OllamaRole::user,
+            content: prompt,
+        });
+        let request = OllamaChatRequest {
+            model: &self.model,
+            messages,
+            options: &self.options,
+            stream,
+        };
+
+        let client = &self.http;
+        let response = client
+            .post(format!("{}/api/chat", self.url.trim_end_matches('/')))
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| {
+                tracing::error!("Error sending request to ollama: {}", e);
+                ModelError::UpstreamModelError
+            })?;
+
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            tracing::error!("Rate limit exceeded");
+            return Err(ModelError::RateLimitExceeded(rate_limit_wait(&response)));
+        }
+        if response.status().is_client_error() || response.status().is_server_error() {
+            tracing::error!(
+                "Error from ollama: {}",
+                response
+                    .text()
+                    .await
+                    .unwrap_or_else(|_| "Unknown".to_string())
+            );
+            return Err(ModelError::UpstreamModelError);
+        }
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl ChatLlm for OllamaModel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn context_size(&self) -> usize {
+        self.context_size.or(self.options.num_ctx).unwrap_or(2048)
+    }
+
+    /// Healthy when the server is up and has pulled the model
+    async fn health_check(&self, _secrets: Secrets) -> Result<(), ModelError> {
+        let tags: OllamaTags = self
+            .http
+            .get(format!("{}/api/tags", self.url.trim_end_matches('/')))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| ModelError::Other(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| ModelError::Other(e.to_string()))?;
+        // A model pulled without a tag is listed as `:latest`
+        let latest = format!("{}:latest", self.model);
+        if tags
+            .models
+            .iter()
+            .any(|tag| tag.name == self.model || tag.name == latest)
+        {
+            Ok(())
+        } else {
+            Err(ModelError::Other(format!(
+                "{} has not been pulled",
+                self.model
+            )))
+        }
+    }
+
+    async fn chat(
+        &self,
+        _secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<String, ModelError> {
+        let response = self.send(prompt, system, history, false).await?;
+
+        let response: OllamaChatResponse = response.json().await.map_err(|e| {
+            tracing::error!("Error parsing response from ollama: {}", e);
+            ModelError::UpstreamModelError
+        })?;
+        if let Some(error) = response.error {
+            tracing::error!("Error from ollama: {}", error);
+            return Err(ModelError::UpstreamModelError);
+        }
+        response.message.map(|message| message.content).ok_or_else(|| {
+            tracing::error!("No generation in response from ollama");
+            ModelError::UpstreamModelError
+        })
+    }
+
+    async fn chat_stream(
+        &self,
+        _secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<ChatStream, ModelError> {
+        let response = self.send(prompt, system, history, true).await?;
+
+        // Ollama streams newline delimited JSON rather than server-sent events
+        let tokens = lines(response.bytes_stream()).filter_map(|line| async move {
+            match line {
+                Ok(line) => OllamaChatResponse::parse_token(&line),
+                Err(e) => Some(Err(e)),
+            }
+        });
+        Ok(Box::pin(tokens))
+    }
+}
@@ -1,13 +1,30 @@
 use crate::{
-    chat::{chat_trait::ChatLlm, errors::ModelError, History},
+    chat::{
+        api_keys::ApiKeys,
+        chat_trait::ChatLlm,
+        errors::ModelError,
+        http::HttpClient,
+        retry::{rate_limit_wait, RetryPolicy},
+        stream::{sse_data, ChatStream},
+        History,
+    },
     secret_manager::Secrets,
 };
 use async_trait::async_trait;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::Path};
-use reqwest::Client;
+use std::collections::HashMap;
 
 const API_URL_V1: &str = "https://api.openai.com/v1";
+const API_KEY_SECRET: &str = "OPENAI_API_TOKEN";
+
+fn default_base_url() -> String {
+    API_URL_V1.to_string()
+}
+
+fn default_api_key_secret() -> Option<String> {
+    Some(API_KEY_SECRET.to_string())
+}
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct OpenAIParameters {
@@ -41,6 +58,57 @@ pub struct ChatCompletionRequest {
     pub logit_bias: Option<HashMap<String, i32>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+}
+
+impl ChatCompletionRequest {
+    pub fn new(
+        model: String,
+        parameters: &OpenAIParameters,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Self {
+        let mut messages = Vec::new();
+        if let Some(system) = system {
+            messages.push(ChatCompletionMessage {
+                role: MessageRole::system,
+                content: system,
+                name: None,
+            });
+        }
+        for h in history {
+            messages.push(ChatCompletionMessage {
+                role: MessageRole::user,
+                content: h.prompt,
+                name: None,
+            });
+            messages.push(ChatCompletionMessage {
+                role: MessageRole::assistant,
+                content: h.generation,
+                name: None,
+            });
+        }
+        messages.push(ChatCompletionMessage {
+            role: MessageRole::user,
+            content: prompt,
+            name: None,
+        });
+        Self {
+            model,
+            messages,
+            temperature: parameters.temperature,
+            top_p: parameters.top_p,
+            stop: parameters.stop.clone(),
+            max_tokens: parameters.max_tokens,
+            presence_penalty: parameters.presence_penalty,
+            frequency_penalty: parameters.frequency_penalty,
+            logit_bias: parameters.logit_bias.clone(),
+            user: parameters.user.clone(),
+            stream: None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -70,6 +138,7 @@ pub struct ChatCompletionMessageForResponse {
 }
 
 #[derive(Debug, Deserialize)]
+#[allow(dead_code)]
 pub struct ChatCompletionChoice {
     pub index: i64,
     pub message: ChatCompletionMessageForResponse,
@@ -77,6 +146,7 @@ pub struct ChatCompletionChoice {
 }
 
 #[derive(Debug, Deserialize)]
+#[allow(dead_code)]
 pub struct Usage {
     pub prompt_tokens: i32,
     pub completion_tokens: i32,
@@ -84,6 +154,7 @@ pub struct Usage {
 }
 
 #[derive(Debug, Deserialize)]
+#[allow(dead_code)]
 pub struct ChatCompletionResponse {
     pub id: String,
     pub object: String,
@@ -93,6 +164,60 @@ pub struct ChatCompletionResponse {
     pub usage: Usage,
 }
 
+impl ChatCompletionResponse {
+    pub fn into_generation(mut self) -> Result<String, ModelError> {
+        let choice = self.choices.pop().ok_or_else(|| {
+            tracing::error!("No generation in response from openai");
+            ModelError::UpstreamModelError
+        })?;
+        if let FinishReason::content_filter = choice.finish_reason {
+            tracing::error!("Generation blocked by the content filter");
+            return Err(ModelError::ContentFiltered);
+        }
+        choice.message.content.ok_or_else(|| {
+            tracing::error!("No generation in response from openai");
+            ModelError::UpstreamModelError
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionDelta {
+    pub content: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionChunkChoice {
+    pub delta: ChatCompletionDelta,
+    pub finish_reason: Option<FinishReason>,
+}
+
+/// A single server-sent event from a `stream: true` chat completion
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionChunk {
+    pub choices: Vec<ChatCompletionChunkChoice>,
+}
+
+impl ChatCompletionChunk {
+    /// Parse the `data:` payload of an event into its token, if it carries one
+    pub fn parse_token(data: &str) -> Option<Result<String, ModelError>> {
+        match serde_json::from_str::<ChatCompletionChunk>(data) {
+            Ok(mut chunk) => {
+                let choice = chunk.choices.pop()?;
+                if let Some(FinishReason::content_filter) = choice.finish_reason {
+                    tracing::error!("Generation blocked by the content filter");
+                    return Some(Err(ModelError::ContentFiltered));
+                }
+                choice.delta.content.map(Ok)
+            }
+            Err(e) => {
+                tracing::error!("Error parsing stream chunk from openai: {}", e);
+                Some(Err(ModelError::UpstreamModelError))
+            }
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[allow(non_camel_case_types)]
 pub enum FinishReason {
@@ -109,78 +234,78 @@ pub struct OpenAIModel {
     pub model: String,
     pub parameters: OpenAIParameters,
     pub context_size: usize,
+    /// Root of any server that speaks the chat/completions schema (vLLM, llama.cpp, LocalAI, ...)
+    #[serde(default = "default_base_url")]
+    pub base_url: String,
+    /// Name of the secret holding the bearer token. `null` sends no `Authorization` header.
+    #[serde(default = "default_api_key_secret")]
+    pub api_key_secret: Option<String>,
+    /// Names of several secrets holding bearer tokens, used in turn in place of `api_key_secret`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub api_key_secrets: Vec<String>,
+    #[serde(skip)]
+    pub keys: ApiKeys,
+    /// Extra headers sent with every request
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(flatten)]
+    pub http: HttpClient,
 }
 
-#[async_trait]
-impl ChatLlm for OpenAIModel {
-    fn name(&self) -> &str {
-        &self.name
+impl OpenAIModel {
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url.trim_end_matches('/'), path)
     }
 
-    fn context_size(&self) -> usize {
-        250
+    /// The secrets holding the bearer tokens, none when the server doesn't need one
+    fn key_secrets(&self) -> Vec<&str> {
+        if self.api_key_secrets.is_empty() {
+            self.api_key_secret.iter().map(String::as_str).collect()
+        } else {
+            self.api_key_secrets.iter().map(String::as_str).collect()
+        }
     }
 
-    async fn chat(
+    /// Add the bearer token and the extra headers
+    fn authorize(
         &self,
-        secrets: Secrets,
-        prompt: String,
-        system: Option<String>,
-        history: Vec<History>,
-    ) -> Result<String, ModelError> {
-        let mut messages = Vec::new();
-        if let Some(system) = system {
-            messages.push(ChatCompletionMessage {
-                role: MessageRole::system,
-                content: system,
-                name: None,
-            });
+        auth_token: Option<&str>,
+        mut builder: reqwest::RequestBuilder,
+    ) -> reqwest::RequestBuilder {
+        if let Some(auth_token) = auth_token {
+            builder = builder.header("Authorization", format!("Bearer {}", auth_token));
         }
-        for h in history {
-            messages.push(ChatCompletionMessage {
-                role: MessageRole::user,
-                content: h.prompt,
-                name: None,
-            });
-            messages.push(ChatCompletionMessage {
-                role: MessageRole::assistant,
-                content: h.generation,
-                name: None,
-            });
+        for (header, value) in &self.headers {
+            builder = builder.header(header, value);
         }
-        messages.push(ChatCompletionMessage {
-            role: MessageRole::user,
-            content: prompt,
-            name: None,
-        });
-        let request = ChatCompletionRequest {
-            model: self.model.clone(),
-            messages,
-            temperature: self.parameters.temperature,
-            top_p: self.parameters.top_p,
-            stop: self.parameters.stop.clone(),
-            max_tokens: self.parameters.max_tokens,
-            presence_penalty: self.parameters.presence_penalty,
-            frequency_penalty: self.parameters.frequency_penalty,
-            logit_bias: self.parameters.logit_bias.clone(),
-            user: self.parameters.user.clone(),
-        };
-
-        let auth_token = secrets
-            .get_secret("OPENAI_API_TOKEN")
-            .await
-            .ok_or(ModelError::Other("Missing Auth".to_string()))?;
+        builder
+    }
 
-        let client = Client::new();
-        let response = client
-            .post(format!("{}/chat/completions", API_URL_V1))
-            .json(&request)
-            .header("Authorization", format!("Bearer {}", auth_token))
-            .send()
+    async fn send(
+        &self,
+        secrets: Secrets,
+        request: &ChatCompletionRequest,
+    ) -> Result<reqwest::Response, ModelError> {
+        let client = &self.http;
+        let keys = self
+            .keys
+            .lookup(&self.name, &secrets, &self.key_secrets())
+            .await?;
+        let response = self
+            .retry
+            .send(|| {
+                // A rate limited key moves on to the next one before the attempt counts
+                self.keys.send(&self.name, &keys, |auth_token| {
+                    let builder = client.post(self.endpoint("chat/completions")).json(request);
+                    self.authorize(auth_token, builder).send()
+                })
+            })
             .await
             .map_err(|e| {
                 tracing::error!("Error sending request to openai: {}", e);
-                ModelError::UpstreamModelError
+                ModelError::from(e)
             })?;
 
         if response.status().is_server_error() {
@@ -198,7 +323,7 @@ impl ChatLlm for OpenAIModel {
             match response.status() {
                 reqwest::StatusCode::TOO_MANY_REQUESTS => {
                     tracing::error!("Rate limit exceeded");
-                    return Err(ModelError::RateLimitExceeded(1000));
+                    return Err(ModelError::RateLimitExceeded(rate_limit_wait(&response)));
                 }
                 _ => {
                     tracing::error!(
@@ -212,33 +337,89 @@ impl ChatLlm for OpenAIModel {
                 }
             }
         }
+        Ok(response)
+    }
+}
 
-        let mut response: ChatCompletionResponse = response.json().await.map_err(|e| {
+#[async_trait]
+impl ChatLlm for OpenAIModel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn context_size(&self) -> usize {
+        self.context_size
+    }
+
+    fn secrets(&self) -> Vec<&str> {
+        self.key_secrets()
+    }
+
+    /// Lists the models, which checks the server is up and the credentials are good without
+    /// paying for a generation
+    async fn health_check(&self, secrets: Secrets) -> Result<(), ModelError> {
+        let keys = self
+            .keys
+            .lookup(&self.name, &secrets, &self.key_secrets())
+            .await?;
+        self.authorize(keys.first(), self.http.get(self.endpoint("models")))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| ModelError::Other(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn chat(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<String, ModelError> {
+        let request = ChatCompletionRequest::new(
+            self.model.clone(),
+            &self.parameters,
+            prompt,
+            system,
+            history,
+        );
+        let response = self.send(secrets, &request).await?;
+
+        let response: ChatCompletionResponse = response.json().await.map_err(|e| {
             tracing::error!("Error parsing response from openai: {}", e);
             ModelError::UpstreamModelError
         })?;
-        
-        let response = response.choices.pop().ok_or_else(|| {
-            tracing::error!("No generation in response from openai");
-            ModelError::UpstreamModelError
-        })?;
-        let response = response.message;
-        response.content.ok_or_else(|| {
-            tracing::error!("No generation in response from openai");
-            ModelError::UpstreamModelError
-        })
+        response.into_generation()
     }
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct OpenAIModels {
-    pub models: Vec<OpenAIModel>,
-}
+    async fn chat_stream(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<ChatStream, ModelError> {
+        let mut request = ChatCompletionRequest::new(
+            self.model.clone(),
+            &self.parameters,
+            prompt,
+            system,
+            history,
+        );
+        request.stream = Some(true);
+        let response = self.send(secrets, &request).await?;
 
-impl OpenAIModels {
-    pub fn new<P: AsRef<Path> + Send + Sync>(file: P) -> anyhow::Result<Self> {
-        let file = std::fs::File::open(file)?;
-        let models: Vec<OpenAIModel> = serde_json::from_reader(file)?;
-        Ok(Self { models })
+        let tokens = sse_data(response)
+            .take_while(|data| {
+                futures::future::ready(!matches!(data, Ok(data) if data == "[DONE]"))
+            })
+            .filter_map(|data| async move {
+                match data {
+                    Ok(data) => ChatCompletionChunk::parse_token(&data),
+                    Err(e) => Some(Err(e)),
+                }
+            });
+        Ok(Box::pin(tokens))
     }
 }
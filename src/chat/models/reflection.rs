@@ -4,9 +4,6 @@ use crate::{
 };
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use serde_json;
-use std::collections::HashMap;
-use std::path::Path;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ReflectionModel {
@@ -34,18 +31,3 @@ impl ChatLlm for ReflectionModel {
         Ok(format!("prompt: {}", prompt))
     }
 }
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct ReflectionModels {
-    // pub models: Vec<ReflectionModel>,
-    pub models: HashMap<String, ReflectionModel>,
-}
-
-impl ReflectionModels {
-    pub fn new<P: AsRef<Path> + Send + Sync>(file: P) -> Self {
-        let contents = std::fs::read_to_string(file).unwrap();
-        // let models: Vec<ReflectionModel> = serde_json::from_str(&contents).unwrap();
-        let models: HashMap<String, ReflectionModel> = serde_json::from_str(&contents).unwrap();
-        Self { models }
-    }
-}
@@ -0,0 +1,304 @@
+use super::huggingface::{HuggingFaceModelParameters, HuggingFacePromptFormat, StreamResponse};
+use crate::{
+    chat::{
+        chat_trait::ChatLlm,
+        errors::ModelError,
+        http::HttpClient,
+        replicas::{ReplicaGuard, Replicas},
+        retry::{rate_limit_wait, RetryPolicy},
+        stream::{sse_data, ChatStream},
+        GenerationDetails, History,
+    },
+    secret_manager::Secrets,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Used when `context_size` is not configured and the server's `/info` can't be read
+const DEFAULT_CONTEXT_SIZE: usize = 2048;
+/// Loading the models waits on `/info`, so a replica that doesn't answer is given up on quickly
+const INFO_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+struct TgiParameters<'a> {
+    #[serde(flatten)]
+    parameters: &'a HuggingFaceModelParameters,
+    details: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct TgiRequest<'a> {
+    inputs: String,
+    parameters: TgiParameters<'a>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TgiDetails {
+    finish_reason: String,
+    generated_tokens: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct TgiResponse {
+    generated_text: String,
+    details: Option<TgiDetails>,
+}
+
+/// The parts of text-generation-inference's `/info` we care about
+#[derive(Debug, Deserialize)]
+struct TgiInfo {
+    model_id: String,
+    max_input_length: usize,
+}
+
+/// A model served directly by text-generation-inference, such as the deployments in `kube/`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TgiModel {
+    pub name: String,
+    /// Root of the server in `url`, or of each server in `replicas`. `/generate`,
+    /// `/generate_stream` and `/info` are appended.
+    #[serde(flatten)]
+    pub replicas: Replicas,
+    pub parameters: HuggingFaceModelParameters,
+    pub prompt_format: HuggingFacePromptFormat,
+    /// Read from the server's `/info` when not set
+    pub context_size: Option<usize>,
+    /// Name of the secret holding a bearer token, for servers behind an authenticating proxy
+    #[serde(default)]
+    pub api_key_secret: Option<String>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(flatten)]
+    pub http: HttpClient,
+}
+
+fn endpoint(url: &str, path: &str) -> String {
+    format!("{}/{}", url.trim_end_matches('/'), path)
+}
+
+impl TgiModel {
+    /// Replicas serve the same model, so the first one to answer is used
+    async fn fetch_info(&self) -> anyhow::Result<TgiInfo> {
+        let mut errors = Vec::new();
+        for url in self.replicas.urls() {
+            match self.fetch_replica_info(url).await {
+                Ok(info) => return Ok(info),
+                Err(e) => errors.push(format!("{}: {}", url, e)),
+            }
+        }
+        anyhow::bail!(errors.join(", "))
+    }
+
+    async fn fetch_replica_info(&self, url: &str) -> reqwest::Result<TgiInfo> {
+        self.http
+            .get(endpoint(url, "info"))
+            .timeout(INFO_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    async fn send(
+        &self,
+        secrets: Secrets,
+        path: &str,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<(reqwest::Response, ReplicaGuard), ModelError> {
+        let request = TgiRequest {
+            inputs: self
+                .prompt_format
+                .format(system.as_deref(), &prompt, &history),
+            parameters: TgiParameters {
+                parameters: &self.parameters,
+                details: true,
+            },
+        };
+
+        let auth_token = match &self.api_key_secret {
+            Some(api_key_secret) => Some(
+                secrets
+                    .get_secret(api_key_secret)
+                    .await
+                    .ok_or(ModelError::Other("Missing Auth".to_string()))?,
+            ),
+            None => None,
+        };
+        let client = &self.http;
+        let (response, replica) = self
+            .retry
+            .send(|| {
+                self.replicas.send(|url| {
+                    let builder = client.post(endpoint(url, path)).json(&request);
+                    match &auth_token {
+                        Some(auth_token) => {
+                            builder.header("Authorization", format!("Bearer {}", auth_token))
+                        }
+                        None => builder,
+                    }
+                })
+            })
+            .await
+            .map_err(|e| {
+                tracing::error!("Error sending request to tgi: {}", e);
+                ModelError::UpstreamModelError
+            })?;
+
+        match response.status() {
+            reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                tracing::error!("Rate limit exceeded");
+                Err(ModelError::RateLimitExceeded(rate_limit_wait(&response)))
+            }
+            reqwest::StatusCode::UNPROCESSABLE_ENTITY => {
+                // TGI validates the input length against its own limits, as well as the parameters
+                let body = response
+                    .text()
+                    .await
+                    .unwrap_or_else(|_| "Unknown".to_string());
+                tracing::error!("Input rejected by tgi: {}", body);
+                if body.contains("`inputs`") {
+                    Err(ModelError::PromptTooLong)
+                } else {
+                    Err(ModelError::UpstreamModelError)
+                }
+            }
+            status if status.is_client_error() || status.is_server_error() => {
+                tracing::error!(
+                    "Error from tgi: {}",
+                    response
+                        .text()
+                        .await
+                        .unwrap_or_else(|_| "Unknown".to_string())
+                );
+                Err(ModelError::UpstreamModelError)
+            }
+            _ => Ok((response, replica)),
+        }
+    }
+}
+
+#[async_trait]
+impl ChatLlm for TgiModel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn init(&mut self) -> anyhow::Result<()> {
+        if self.context_size.is_some() {
+            return Ok(());
+        }
+        match self
+            .fetch_info()
+            .await
+            .with_context(|| format!("Failed to read info for {}", self.name))
+        {
+            Ok(info) => {
+                tracing::info!(
+                    "{} is serving {} with a context size of {}",
+                    self.name,
+                    info.model_id,
+                    info.max_input_length
+                );
+                self.context_size = Some(info.max_input_length);
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "{:#}, using a context size of {}. Set context_size to skip the lookup",
+                    e,
+                    DEFAULT_CONTEXT_SIZE
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn context_size(&self) -> usize {
+        self.context_size.unwrap_or(DEFAULT_CONTEXT_SIZE)
+    }
+
+    fn secrets(&self) -> Vec<&str> {
+        self.api_key_secret.iter().map(String::as_str).collect()
+    }
+
+    /// Healthy while any replica is
+    async fn health_check(&self, _secrets: Secrets) -> Result<(), ModelError> {
+        let checks = self.replicas.urls().map(|url| async move {
+            self.http
+                .get(endpoint(url, "health"))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| format!("{}: {}", url, e))
+        });
+        let (healthy, errors): (Vec<_>, Vec<_>) = futures::future::join_all(checks)
+            .await
+            .into_iter()
+            .partition(Result::is_ok);
+        if healthy.is_empty() {
+            let errors: Vec<String> = errors.into_iter().filter_map(Result::err).collect();
+            return Err(ModelError::Other(errors.join(", ")));
+        }
+        Ok(())
+    }
+
+    async fn chat(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<String, ModelError> {
+        let (generation, _) = self
+            .chat_with_details(secrets, prompt, system, history)
+            .await?;
+        Ok(generation)
+    }
+
+    async fn chat_with_details(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<(String, Option<GenerationDetails>), ModelError> {
+        let (response, _replica) = self
+            .send(secrets, "generate", prompt, system, history)
+            .await?;
+
+        let response: TgiResponse = response.json().await.map_err(|e| {
+            tracing::error!("Error parsing response from tgi: {}", e);
+            ModelError::UpstreamModelError
+        })?;
+        let details = response.details.map(|details| GenerationDetails {
+            finish_reason: details.finish_reason,
+            generated_tokens: details.generated_tokens,
+        });
+        Ok((response.generated_text, details))
+    }
+
+    async fn chat_stream(
+        &self,
+        secrets: Secrets,
+        prompt: String,
+        system: Option<String>,
+        history: Vec<History>,
+    ) -> Result<ChatStream, ModelError> {
+        let (response, replica) = self
+            .send(secrets, "generate_stream", prompt, system, history)
+            .await?;
+
+        let tokens = sse_data(response).filter_map(|data| async move {
+            match data {
+                Ok(data) => StreamResponse::parse_token(&data),
+                Err(e) => Some(Err(e)),
+            }
+        });
+        Ok(replica.hold(Box::pin(tokens)))
+    }
+}
@@ -0,0 +1,266 @@
+//! Per-user and per-team quotas
+//!
+//! Usage is counted in redis over fixed hourly and daily windows (UTC), keyed by the `user` and
+//! `team` a request is made on behalf of. Quotas are configured in `MODEL_DIR/quotas.yaml`.
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Counts a request against every window in `KEYS` if none of them is spent, otherwise counts
+/// nothing and returns the failing window with its usage. `ARGV` is the ttl, request limit and
+/// token limit for each key (-1 for no limit), followed by the tokens the request costs.
+const ACQUIRE_SCRIPT: &str = r#"
+local cost = tonumber(ARGV[#ARGV])
+for i, key in ipairs(KEYS) do
+    local requests = tonumber(redis.call('HGET', key, 'requests')) or 0
+    local tokens = tonumber(redis.call('HGET', key, 'tokens')) or 0
+    local request_limit = tonumber(ARGV[i * 3 - 1])
+    local token_limit = tonumber(ARGV[i * 3])
+    if (request_limit >= 0 and requests + 1 > request_limit)
+        or (token_limit >= 0 and tokens + cost > token_limit) then
+        return {i, requests, tokens}
+    end
+end
+for i, key in ipairs(KEYS) do
+    redis.call('HINCRBY', key, 'requests', 1)
+    redis.call('HINCRBY', key, 'tokens', cost)
+    redis.call('EXPIRE', key, tonumber(ARGV[i * 3 - 2]))
+end
+return {0}
+"#;
+
+/// Gives back a request counted by `ACQUIRE_SCRIPT`, leaving alone windows that have expired
+/// since. `ARGV` is the tokens the request cost.
+const REFUND_SCRIPT: &str = r#"
+local cost = tonumber(ARGV[1])
+for i, key in ipairs(KEYS) do
+    if redis.call('EXISTS', key) == 1 then
+        redis.call('HINCRBY', key, 'requests', -1)
+        redis.call('HINCRBY', key, 'tokens', -cost)
+    end
+end
+return 0
+"#;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QuotaLimits {
+    pub requests: Option<u64>,
+    pub tokens: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Quota {
+    pub hourly: Option<QuotaLimits>,
+    pub daily: Option<QuotaLimits>,
+}
+
+/// The quota every user and every team gets
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Quotas {
+    pub user: Option<Quota>,
+    pub team: Option<Quota>,
+}
+
+/// Where a caller stands against the quota they ran out of
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaStatus {
+    /// Who ran out, e.g. `team:red`
+    pub identity: String,
+    /// `hourly` or `daily`
+    pub window: String,
+    pub requests_remaining: Option<u64>,
+    pub tokens_remaining: Option<u64>,
+    /// Unix time in seconds when the window resets
+    pub resets_at: u64,
+}
+
+impl QuotaStatus {
+    /// Seconds until the window resets
+    pub fn resets_in(&self) -> u64 {
+        self.resets_at.saturating_sub(now())
+    }
+}
+
+struct Window<'a> {
+    identity: String,
+    name: &'static str,
+    limits: &'a QuotaLimits,
+    key: String,
+    resets_at: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+impl Quotas {
+    /// Read `quotas.json`, `quotas.yaml` or `quotas.yml` from `model_dir`. Without one there are
+    /// no quotas.
+    pub fn load(model_dir: &Path) -> anyhow::Result<Self> {
+        for extension in ["json", "yaml", "yml"] {
+            let path = model_dir.join("quotas").with_extension(extension);
+            if !path.exists() {
+                continue;
+            }
+            let file = std::fs::File::open(&path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            let quotas = match extension {
+                "json" => serde_json::from_reader(file)?,
+                _ => serde_yaml::from_reader(file)?,
+            };
+            tracing::info!("Loaded quotas from {}", path.display());
+            return Ok(quotas);
+        }
+        Ok(Self::default())
+    }
+
+    fn windows(&self, user: Option<&str>, team: Option<&str>) -> Vec<Window<'_>> {
+        let now = now();
+        let identities = [("user", user, &self.user), ("team", team, &self.team)];
+        let mut windows = Vec::new();
+        for (kind, id, quota) in identities {
+            let (Some(id), Some(quota)) = (id, quota) else {
+                continue;
+            };
+            let identity = format!("{}:{}", kind, id);
+            let periods = [
+                ("hourly", &quota.hourly, 60 * 60),
+                ("daily", &quota.daily, 24 * 60 * 60),
+            ];
+            for (name, limits, length) in periods {
+                let Some(limits) = limits else {
+                    continue;
+                };
+                let start = now / length * length;
+                windows.push(Window {
+                    key: format!("quota:{}:{}:{}", identity, name, start),
+                    identity: identity.clone(),
+                    name,
+                    limits,
+                    resets_at: start + length,
+                });
+            }
+        }
+        windows
+    }
+
+    /// Count a request of `tokens` prompt tokens against the caller's quotas. Returns the keys
+    /// of the windows it was counted in, to [`Quotas::refund`] it from, or the quota the caller
+    /// has used up.
+    pub async fn acquire(
+        &self,
+        redis_client: &redis::Client,
+        user: Option<&str>,
+        team: Option<&str>,
+        tokens: usize,
+    ) -> anyhow::Result<Result<Vec<String>, QuotaStatus>> {
+        let windows = self.windows(user, team);
+        if windows.is_empty() {
+            return Ok(Ok(Vec::new()));
+        }
+        let mut redis_connection = redis_client
+            .get_async_connection()
+            .await
+            .context("Failed to get redis connection")?;
+
+        let script = redis::Script::new(ACQUIRE_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        let now = now();
+        for window in &windows {
+            let limit = |limit: Option<u64>| limit.map(|limit| limit as i64).unwrap_or(-1);
+            invocation
+                .key(&window.key)
+                // Keep the counts a little past the reset to ride out clock skew between routers
+                .arg(window.resets_at - now + 60)
+                .arg(limit(window.limits.requests))
+                .arg(limit(window.limits.tokens));
+        }
+        invocation.arg(tokens);
+        let result: Vec<u64> = invocation
+            .invoke_async(&mut redis_connection)
+            .await
+            .context("Failed to update quotas")?;
+
+        let [index, requests, used_tokens] = result[..] else {
+            return Ok(Ok(windows.into_iter().map(|window| window.key).collect()));
+        };
+        let window = &windows[index as usize - 1];
+        Ok(Err(QuotaStatus {
+            identity: window.identity.clone(),
+            window: window.name.to_string(),
+            requests_remaining: window
+                .limits
+                .requests
+                .map(|limit| limit.saturating_sub(requests)),
+            tokens_remaining: window
+                .limits
+                .tokens
+                .map(|limit| limit.saturating_sub(used_tokens)),
+            resets_at: window.resets_at,
+        }))
+    }
+
+    /// Give back a request of `tokens` prompt tokens taken by `acquire` from the windows in
+    /// `keys`, for a request that failed before the upstream generated anything. The windows are
+    /// the ones it was counted in, even if new ones have started since.
+    pub async fn refund(
+        &self,
+        redis_client: &redis::Client,
+        keys: &[String],
+        tokens: usize,
+    ) -> anyhow::Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut redis_connection = redis_client
+            .get_async_connection()
+            .await
+            .context("Failed to get redis connection")?;
+
+        let script = redis::Script::new(REFUND_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        for key in keys {
+            invocation.key(key);
+        }
+        invocation
+            .arg(tokens)
+            .invoke_async(&mut redis_connection)
+            .await
+            .context("Failed to update quotas")
+    }
+
+    /// Count the generated tokens against the caller's quotas
+    pub async fn charge(
+        &self,
+        redis_client: &redis::Client,
+        user: Option<&str>,
+        team: Option<&str>,
+        tokens: usize,
+    ) -> anyhow::Result<()> {
+        let windows = self.windows(user, team);
+        if windows.is_empty() {
+            return Ok(());
+        }
+        let mut redis_connection = redis_client
+            .get_async_connection()
+            .await
+            .context("Failed to get redis connection")?;
+
+        let mut pipe = redis::pipe();
+        let now = now();
+        for window in &windows {
+            pipe.hincr(&window.key, "tokens", tokens)
+                .ignore()
+                .expire(&window.key, (window.resets_at - now + 60) as usize)
+                .ignore();
+        }
+        pipe.query_async::<_, ()>(&mut redis_connection)
+            .await
+            .context("Failed to update quotas")
+    }
+}
@@ -0,0 +1,119 @@
+//! Per-model rate limits
+//!
+//! Each limit is a token bucket in Redis, so every router replica draws from the same buckets.
+//! Buckets refill continuously over a minute and are updated atomically by a Lua script.
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU64;
+
+/// Takes `cost` from every bucket in `KEYS` if all of them can afford it, otherwise takes nothing
+/// and returns the milliseconds until they can. With `force` the cost is always taken, which may
+/// leave a bucket in debt. `ARGV` is `force` followed by a capacity and cost for each key.
+const TAKE_SCRIPT: &str = r#"
+redis.replicate_commands()
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local force = ARGV[1] == '1'
+local levels = {}
+local wait = 0
+for i, key in ipairs(KEYS) do
+    local capacity = tonumber(ARGV[i * 2])
+    local cost = tonumber(ARGV[i * 2 + 1])
+    local rate = capacity / 60000
+    local bucket = redis.call('HMGET', key, 'level', 'updated')
+    local level = tonumber(bucket[1]) or capacity
+    local updated = tonumber(bucket[2]) or now
+    level = math.min(capacity, level + math.max(0, now - updated) * rate)
+    levels[i] = level
+    -- A cost bigger than the bucket is let through once it is full
+    local needed = math.min(cost, capacity)
+    if level < needed then
+        wait = math.max(wait, math.ceil((needed - level) / rate))
+    end
+end
+if force or wait == 0 then
+    for i, key in ipairs(KEYS) do
+        redis.call('HSET', key, 'level', levels[i] - tonumber(ARGV[i * 2 + 1]), 'updated', now)
+        redis.call('PEXPIRE', key, 120000)
+    end
+    return 0
+end
+return wait
+"#;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub requests_per_minute: Option<NonZeroU64>,
+    pub tokens_per_minute: Option<NonZeroU64>,
+}
+
+impl RateLimit {
+    /// Take a request and `tokens` prompt tokens from the model's buckets. Returns how many
+    /// milliseconds to wait if the model is over its limits.
+    pub async fn acquire(
+        &self,
+        redis_client: &redis::Client,
+        model: &str,
+        tokens: usize,
+    ) -> anyhow::Result<Option<u64>> {
+        let mut buckets = Vec::new();
+        if let Some(requests_per_minute) = self.requests_per_minute {
+            buckets.push(("requests", requests_per_minute.get(), 1));
+        }
+        if let Some(tokens_per_minute) = self.tokens_per_minute {
+            buckets.push(("tokens", tokens_per_minute.get(), tokens as u64));
+        }
+        let wait = take(redis_client, model, &buckets, false).await?;
+        Ok((wait > 0).then_some(wait))
+    }
+
+    /// Take the generated tokens from the model's token bucket. The generation has already
+    /// happened, so this can leave the bucket in debt and hold back the next requests.
+    pub async fn charge(
+        &self,
+        redis_client: &redis::Client,
+        model: &str,
+        tokens: usize,
+    ) -> anyhow::Result<()> {
+        if let Some(tokens_per_minute) = self.tokens_per_minute {
+            take(
+                redis_client,
+                model,
+                &[("tokens", tokens_per_minute.get(), tokens as u64)],
+                true,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+async fn take(
+    redis_client: &redis::Client,
+    model: &str,
+    buckets: &[(&str, u64, u64)],
+    force: bool,
+) -> anyhow::Result<u64> {
+    if buckets.is_empty() {
+        return Ok(0);
+    }
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .context("Failed to get redis connection")?;
+
+    let script = redis::Script::new(TAKE_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    invocation.arg(if force { "1" } else { "0" });
+    for (bucket, capacity, cost) in buckets {
+        invocation
+            .key(format!("rate_limit:{}:{}", model, bucket))
+            .arg(capacity)
+            .arg(cost);
+    }
+    invocation
+        .invoke_async(&mut redis_connection)
+        .await
+        .context("Failed to update rate limit buckets")
+}
@@ -0,0 +1,123 @@
+//! Backend registry
+//!
+//! Maps the `type` of a model config entry to the backend that serves it.
+
+use super::chat_trait::ChatLlm;
+use super::models::{
+    AnthropicModel, AzureOpenAIModel, CohereModel, GeminiModel, HuggingFaceModel, MockModel,
+    OllamaModel, OpenAIModel, ReflectionModel, TgiModel,
+};
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub type BoxedChatLlm = Box<dyn ChatLlm + Send + Sync>;
+
+type Loader = fn(serde_json::Value) -> anyhow::Result<BoxedChatLlm>;
+
+fn load<T>(entry: serde_json::Value) -> anyhow::Result<BoxedChatLlm>
+where
+    T: ChatLlm + DeserializeOwned + Send + Sync + 'static,
+{
+    Ok(Box::new(serde_json::from_value::<T>(entry)?))
+}
+
+pub struct BackendRegistry {
+    loaders: BTreeMap<&'static str, Loader>,
+}
+
+impl BackendRegistry {
+    pub fn empty() -> Self {
+        Self {
+            loaders: BTreeMap::new(),
+        }
+    }
+
+    /// Serve config entries of type `backend` with `T`
+    pub fn register<T>(&mut self, backend: &'static str)
+    where
+        T: ChatLlm + DeserializeOwned + Send + Sync + 'static,
+    {
+        self.loaders.insert(backend, load::<T>);
+    }
+
+    pub fn contains(&self, backend: &str) -> bool {
+        self.loaders.contains_key(backend)
+    }
+
+    /// Read the model entries from a `.json`, `.yaml` or `.yml` config file. A file holds either a
+    /// list of entries or a map of model names to entries. Files named after a backend, like
+    /// `openai.json`, may leave out the `type` of their entries.
+    pub fn entries(&self, path: &Path) -> anyhow::Result<Vec<serde_json::Value>> {
+        let document: serde_json::Value = match path.extension().and_then(|s| s.to_str()) {
+            Some("json") => serde_json::from_reader(std::fs::File::open(path)?)?,
+            Some("yaml") | Some("yml") => serde_yaml::from_reader(std::fs::File::open(path)?)?,
+            _ => anyhow::bail!("Unrecognised file, model configs must be .json, .yaml or .yml"),
+        };
+        let default_type = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .filter(|stem| self.contains(stem));
+
+        let entries = match document {
+            serde_json::Value::Array(entries) => entries,
+            serde_json::Value::Object(entries) => entries
+                .into_iter()
+                .map(|(name, mut entry)| {
+                    if let Some(entry) = entry.as_object_mut() {
+                        entry.entry("name").or_insert(name.into());
+                    }
+                    entry
+                })
+                .collect(),
+            _ => anyhow::bail!("Expected a list of models or a map of model names to models"),
+        };
+        entries
+            .into_iter()
+            .map(|mut entry| {
+                let fields = entry
+                    .as_object_mut()
+                    .context("Model entries must be objects")?;
+                if let Some(default_type) = default_type {
+                    fields.entry("type").or_insert(default_type.into());
+                }
+                Ok(entry)
+            })
+            .collect()
+    }
+
+    /// Build the model for one config entry, dispatching on its `type`
+    pub fn load(&self, entry: serde_json::Value) -> anyhow::Result<BoxedChatLlm> {
+        let backend = entry
+            .get("type")
+            .and_then(|backend| backend.as_str())
+            .map(str::to_string)
+            .context("Model entry has no `type`")?;
+        let loader = self.loaders.get(backend.as_str()).with_context(|| {
+            format!(
+                "Unknown model type `{}`, expected one of: {}",
+                backend,
+                self.loaders.keys().cloned().collect::<Vec<_>>().join(", ")
+            )
+        })?;
+        loader(entry).with_context(|| format!("Invalid `{}` model entry", backend))
+    }
+}
+
+impl Default for BackendRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register::<AnthropicModel>("anthropic");
+        registry.register::<AzureOpenAIModel>("azure");
+        registry.register::<CohereModel>("cohere");
+        registry.register::<GeminiModel>("gemini");
+        registry.register::<HuggingFaceModel>("huggingface");
+        registry.register::<MockModel>("mock");
+        registry.register::<OllamaModel>("ollama");
+        registry.register::<OpenAIModel>("openai");
+        registry.register::<ReflectionModel>("reflection");
+        registry.register::<TgiModel>("tgi");
+        registry
+    }
+}
@@ -0,0 +1,158 @@
+//! Hot reload of the model configuration
+//!
+//! The models in service are rebuilt from `MODEL_DIR/chat`, along with the quotas in `MODEL_DIR`,
+//! and swapped in whole. Requests that already picked up the old set finish on it, and a config
+//! that fails to load leaves the old set in service. The secrets every model reads are checked
+//! as it loads, and models whose entries haven't changed keep the state of their circuits. The
+//! gauges of the models a reload drops are removed.
+
+use super::http::{restore_clients, take_clients};
+use super::state::{ChatModels, ModelsResponse};
+use crate::metrics;
+use crate::secret_manager::Secrets;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone)]
+pub struct ReloadableModels {
+    model_dir: Arc<PathBuf>,
+    current: Arc<RwLock<Arc<ChatModels>>>,
+    secrets: Secrets,
+    /// Refuse to load models whose secrets can't be found, rather than only logging them
+    require_secrets: bool,
+    /// Keeps the watcher and the reload endpoint from rebuilding at the same time
+    reloading: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl ReloadableModels {
+    pub async fn load<P: AsRef<Path>>(
+        model_dir: P,
+        secrets: Secrets,
+        require_secrets: bool,
+    ) -> anyhow::Result<Self> {
+        let model_dir = model_dir.as_ref().to_path_buf();
+        let chat_models = build(&model_dir, &secrets, require_secrets, None).await?;
+        Ok(Self {
+            model_dir: Arc::new(model_dir),
+            current: Arc::new(RwLock::new(Arc::new(chat_models))),
+            secrets,
+            require_secrets,
+            reloading: Arc::new(tokio::sync::Mutex::new(())),
+        })
+    }
+
+    /// The models in service right now
+    pub fn current(&self) -> Arc<ChatModels> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Rebuild the models from disk and swap them in, keeping the current set on error
+    pub async fn reload(&self) -> anyhow::Result<ModelsResponse> {
+        let _reloading = self.reloading.lock().await;
+        let chat_models = build(
+            &self.model_dir,
+            &self.secrets,
+            self.require_secrets,
+            Some(&self.current()),
+        )
+        .await?;
+        let models = chat_models.models().await?;
+        let chat_models = Arc::new(chat_models);
+        let previous = std::mem::replace(&mut *self.current.write().unwrap(), chat_models.clone());
+        previous.forget_dropped(&chat_models);
+        tracing::info!("Reloaded models: {:?}", models.models);
+        Ok(models)
+    }
+
+    /// Poll the config every `interval` and reload when any file in it changes
+    pub fn watch(self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut last_seen = self.fingerprint();
+            loop {
+                tokio::time::sleep(interval).await;
+                let latest = self.fingerprint();
+                if latest == last_seen {
+                    continue;
+                }
+                last_seen = latest;
+                tracing::info!("Model config changed in {}", self.model_dir.display());
+                if let Err(e) = self.reload().await {
+                    tracing::error!("Failed to reload models, keeping the current set: {:#}", e);
+                }
+            }
+        })
+    }
+
+    fn fingerprint(&self) -> Vec<(PathBuf, u64, Option<SystemTime>)> {
+        let mut files = fingerprint(&self.model_dir);
+        files.extend(fingerprint(&self.model_dir.join("chat")));
+        files
+    }
+}
+
+/// Load the models in `model_dir` with clients of their own, keeping the circuits of the models
+/// unchanged since `previous`. The clients built before are only dropped once the load succeeds.
+async fn build(
+    model_dir: &Path,
+    secrets: &Secrets,
+    require_secrets: bool,
+    previous: Option<&ChatModels>,
+) -> anyhow::Result<ChatModels> {
+    let clients = take_clients();
+    let chat_models = load(model_dir, secrets, require_secrets, previous).await;
+    if chat_models.is_err() {
+        restore_clients(clients);
+    }
+    chat_models
+}
+
+/// Load the models in `model_dir` and check that the secrets they read can be found
+async fn load(
+    model_dir: &Path,
+    secrets: &Secrets,
+    require_secrets: bool,
+    previous: Option<&ChatModels>,
+) -> anyhow::Result<ChatModels> {
+    let mut chat_models = ChatModels::from_model_dir(model_dir).await?;
+    if let Some(previous) = previous {
+        chat_models.keep_circuits(previous);
+    }
+    let missing = chat_models.missing_secrets(secrets).await;
+    if !missing.is_empty() {
+        let listed: Vec<String> = missing
+            .iter()
+            .map(|(model, secret)| format!("{}: {}", model, secret))
+            .collect();
+        if require_secrets {
+            anyhow::bail!("Missing secrets for models: {}", listed.join(", "));
+        }
+        tracing::error!("Missing secrets for models: {}", listed.join(", "));
+    }
+    metrics::MISSING_SECRETS.reset();
+    for (model, secret) in &missing {
+        metrics::MISSING_SECRETS
+            .with_label_values(&[model, secret])
+            .set(1);
+    }
+    Ok(chat_models)
+}
+
+/// Names, sizes and modification times of the files in `path`. Metadata follows symlinks, so a
+/// mounted kubernetes config map shows up as changed when its `..data` link is swapped.
+fn fingerprint(path: &Path) -> Vec<(PathBuf, u64, Option<SystemTime>)> {
+    let mut files: Vec<_> = std::fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| {
+            let path = entry.path();
+            let metadata = std::fs::metadata(&path).ok();
+            let len = metadata.as_ref().map(|m| m.len()).unwrap_or_default();
+            let modified = metadata.and_then(|m| m.modified().ok());
+            (path, len, modified)
+        })
+        .collect();
+    files.sort();
+    files
+}
@@ -0,0 +1,244 @@
+//! Load balancing across replicas
+//!
+//! Self-hosted backends can serve one model from several servers. Each request is sent to one
+//! replica, picked by the model's `balance` strategy, and a replica that keeps failing is ejected
+//! for a while so traffic goes to the healthy ones.
+
+use crate::chat::stream::ChatStream;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize, Serializer};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_eject_after() -> u32 {
+    3
+}
+
+fn default_eject_seconds() -> u64 {
+    30
+}
+
+/// How the replica for a request is picked
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    /// Round robin, with each replica picked in proportion to its `weight`
+    Weighted,
+    /// The replica with the fewest requests in flight
+    LeastOutstanding,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicaConfig {
+    pub url: String,
+    /// Only used by the `weighted` strategy
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+/// The upstream settings of a replicated model, either a single `url` or a list of `replicas`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicasConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replicas: Vec<ReplicaConfig>,
+    #[serde(default)]
+    pub balance: Balance,
+    /// Consecutive failures before a replica is ejected, 0 never ejects
+    #[serde(default = "default_eject_after")]
+    pub eject_after: u32,
+    /// How long an ejected replica is left out
+    #[serde(default = "default_eject_seconds")]
+    pub eject_seconds: u64,
+}
+
+#[derive(Debug)]
+struct Replica {
+    url: String,
+    weight: u32,
+    outstanding: AtomicUsize,
+    failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Replica {
+    fn is_ejected(&self, now: Instant) -> bool {
+        matches!(*self.ejected_until.lock().unwrap(), Some(until) if until > now)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "ReplicasConfig")]
+pub struct Replicas {
+    config: ReplicasConfig,
+    replicas: Vec<Arc<Replica>>,
+    next: AtomicUsize,
+}
+
+impl TryFrom<ReplicasConfig> for Replicas {
+    type Error = String;
+
+    fn try_from(config: ReplicasConfig) -> Result<Self, Self::Error> {
+        let replicas: Vec<ReplicaConfig> = match (&config.url, config.replicas.is_empty()) {
+            (Some(url), true) => vec![ReplicaConfig {
+                url: url.clone(),
+                weight: default_weight(),
+            }],
+            (None, false) => config.replicas.clone(),
+            (Some(_), false) => return Err("Set either `url` or `replicas`, not both".to_string()),
+            (None, true) => return Err("Missing `url` or `replicas`".to_string()),
+        };
+        if replicas.iter().any(|replica| replica.weight == 0) {
+            return Err("Replica weights must be at least 1".to_string());
+        }
+        Ok(Self {
+            replicas: replicas
+                .into_iter()
+                .map(|replica| {
+                    Arc::new(Replica {
+                        url: replica.url,
+                        weight: replica.weight,
+                        outstanding: AtomicUsize::new(0),
+                        failures: AtomicU32::new(0),
+                        ejected_until: Mutex::new(None),
+                    })
+                })
+                .collect(),
+            config,
+            next: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl Serialize for Replicas {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.config.serialize(serializer)
+    }
+}
+
+impl Replicas {
+    /// Every replica's url, in config order
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        self.replicas.iter().map(|replica| replica.url.as_str())
+    }
+
+    /// Pick the replica for a request. Ejected replicas are skipped, unless every replica is
+    /// ejected, when trying one beats failing outright.
+    pub fn pick(&self) -> ReplicaGuard {
+        let now = Instant::now();
+        let mut available: Vec<&Arc<Replica>> = self
+            .replicas
+            .iter()
+            .filter(|replica| !replica.is_ejected(now))
+            .collect();
+        if available.is_empty() {
+            available = self.replicas.iter().collect();
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        let replica = match self.config.balance {
+            Balance::RoundRobin => available[next % available.len()],
+            Balance::Weighted => {
+                let total: usize = available.iter().map(|r| r.weight as usize).sum();
+                let mut ticket = next % total;
+                let mut picked = available[0];
+                for replica in &available {
+                    if ticket < replica.weight as usize {
+                        picked = replica;
+                        break;
+                    }
+                    ticket -= replica.weight as usize;
+                }
+                picked
+            }
+            // Ties rotate, so idle replicas share the traffic
+            Balance::LeastOutstanding => (0..available.len())
+                .map(|i| available[(next + i) % available.len()])
+                .min_by_key(|replica| replica.outstanding.load(Ordering::Relaxed))
+                .unwrap(),
+        };
+        replica.outstanding.fetch_add(1, Ordering::Relaxed);
+        ReplicaGuard {
+            replica: replica.clone(),
+            eject_after: self.config.eject_after,
+            eject_for: Duration::from_secs(self.config.eject_seconds),
+        }
+    }
+
+    /// Send the request built by `request` for a replica's url to the next replica, counting
+    /// connection errors and server errors against it
+    pub async fn send<F>(&self, request: F) -> reqwest::Result<(reqwest::Response, ReplicaGuard)>
+    where
+        F: FnOnce(&str) -> reqwest::RequestBuilder,
+    {
+        let replica = self.pick();
+        match request(replica.url()).send().await {
+            Ok(response) => {
+                if response.status().is_server_error() {
+                    replica.failed();
+                } else {
+                    replica.succeeded();
+                }
+                Ok((response, replica))
+            }
+            Err(e) => {
+                replica.failed();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A request in flight to one replica. It counts as outstanding until the guard is dropped.
+pub struct ReplicaGuard {
+    replica: Arc<Replica>,
+    eject_after: u32,
+    eject_for: Duration,
+}
+
+impl ReplicaGuard {
+    pub fn url(&self) -> &str {
+        &self.replica.url
+    }
+
+    fn succeeded(&self) {
+        self.replica.failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Count a failure against the replica, ejecting it after `eject_after` in a row
+    fn failed(&self) {
+        let failures = self.replica.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.eject_after == 0 || failures < self.eject_after {
+            return;
+        }
+        tracing::warn!(
+            "Ejecting {} for {}s after {} consecutive failures",
+            self.replica.url,
+            self.eject_for.as_secs(),
+            failures
+        );
+        *self.replica.ejected_until.lock().unwrap() = Some(Instant::now() + self.eject_for);
+        self.replica.failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Keep the request outstanding until `tokens` has finished
+    pub fn hold(self, tokens: ChatStream) -> ChatStream {
+        Box::pin(tokens.map(move |token| {
+            let _ = &self;
+            token
+        }))
+    }
+}
+
+impl Drop for ReplicaGuard {
+    fn drop(&mut self) {
+        self.replica.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,188 @@
+//! Retries of upstream requests
+//!
+//! A model's `retry` policy resends a request that failed to connect or came back with one of
+//! the retryable statuses, backing off exponentially with jitter between attempts.
+
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The longest `Retry-After` taken as is, longer ones are cut down to it
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// The wait in milliseconds for a rate limit that the upstream gives no delay for
+pub const DEFAULT_RATE_LIMIT_WAIT_MS: u64 = 1000;
+
+fn default_max_attempts() -> u32 {
+    1
+}
+
+fn default_base_delay_ms() -> u64 {
+    200
+}
+
+fn default_max_delay_ms() -> u64 {
+    10_000
+}
+
+fn default_jitter() -> f64 {
+    0.2
+}
+
+fn default_statuses() -> Vec<u16> {
+    vec![429, 500, 502, 503, 504]
+}
+
+fn default_deadline_ms() -> u64 {
+    30_000
+}
+
+/// The response of one attempt, so the policy can tell whether to retry it
+pub trait Attempt {
+    fn response(&self) -> &reqwest::Response;
+}
+
+impl Attempt for reqwest::Response {
+    fn response(&self) -> &reqwest::Response {
+        self
+    }
+}
+
+/// A response along with whatever the backend tracks per attempt, such as the replica it used
+impl<T> Attempt for (reqwest::Response, T) {
+    fn response(&self) -> &reqwest::Response {
+        &self.0
+    }
+}
+
+/// Why an attempt got no response, so the policy can tell whether to retry it
+pub trait Failure: std::fmt::Display {
+    /// Whether another attempt would fail the same way, like a request that can't be built
+    fn is_final(&self) -> bool;
+}
+
+impl Failure for reqwest::Error {
+    fn is_final(&self) -> bool {
+        self.is_builder()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Attempts in total, the default of 1 never retries
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// The wait before the first retry, doubled for each one after it
+    #[serde(default = "default_base_delay_ms")]
+    pub base_delay_ms: u64,
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// How far each wait is randomly moved, as a fraction of it
+    #[serde(default = "default_jitter")]
+    pub jitter: f64,
+    /// Response statuses worth retrying. Connection errors are always retried.
+    #[serde(default = "default_statuses")]
+    pub statuses: Vec<u16>,
+    /// No retry is started that would wait past this long after the first attempt
+    #[serde(default = "default_deadline_ms")]
+    pub deadline_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            base_delay_ms: default_base_delay_ms(),
+            max_delay_ms: default_max_delay_ms(),
+            jitter: default_jitter(),
+            statuses: default_statuses(),
+            deadline_ms: default_deadline_ms(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The backoff before retry number `retry`, starting at 1
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay_ms
+            .saturating_mul(1 << (retry - 1).min(32))
+            .min(self.max_delay_ms) as f64;
+        let jitter = self.jitter.clamp(0.0, 1.0) * (fastrand::f64() * 2.0 - 1.0);
+        Duration::from_millis((delay * (1.0 + jitter)) as u64)
+    }
+
+    /// Run `attempt` until it succeeds, fails for good, or the attempts or deadline run out.
+    /// Returns the last attempt's result, so the backend handles a final error status as usual.
+    pub async fn send<T, E, F, Fut>(&self, mut attempt: F) -> Result<T, E>
+    where
+        T: Attempt,
+        E: Failure,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let started = Instant::now();
+        let deadline = Duration::from_millis(self.deadline_ms);
+        let mut retry = 0;
+        loop {
+            let result = attempt().await;
+            retry += 1;
+            if retry >= self.max_attempts {
+                return result;
+            }
+            let delay = match &result {
+                Ok(response) => {
+                    let response = response.response();
+                    if !self.statuses.contains(&response.status().as_u16()) {
+                        return result;
+                    }
+                    retry_after(response).unwrap_or_else(|| self.backoff(retry))
+                }
+                Err(e) if e.is_final() => return result,
+                Err(_) => self.backoff(retry),
+            };
+            if started.elapsed().saturating_add(delay) > deadline {
+                return result;
+            }
+            match &result {
+                Ok(response) => tracing::warn!(
+                    "Upstream returned {}, retrying in {}ms",
+                    response.response().status(),
+                    delay.as_millis()
+                ),
+                Err(e) => tracing::warn!(
+                    "Upstream request failed ({}), retrying in {}ms",
+                    e,
+                    delay.as_millis()
+                ),
+            }
+            drop(result);
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// The upstream's `Retry-After`, in seconds or as an HTTP date, up to `MAX_RETRY_AFTER`
+pub fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?;
+    let wait = match value.trim().parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            date.duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO)
+        }
+    };
+    Some(wait.min(MAX_RETRY_AFTER))
+}
+
+/// The wait in milliseconds for a rate limited response, for a `RateLimitExceeded`
+pub fn rate_limit_wait(response: &reqwest::Response) -> u64 {
+    retry_after(response).map_or(DEFAULT_RATE_LIMIT_WAIT_MS, |wait| {
+        u64::try_from(wait.as_millis()).unwrap_or(u64::MAX)
+    })
+}
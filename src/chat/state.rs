@@ -1,74 +1,244 @@
-use super::models::{HuggingFaceModels, MockModels, ReflectionModels};
-use super::{chat_trait::ChatLlm, errors::ModelError, ChatRequest, ChatResponse};
-use crate::chat::models::OpenAIModels;
-use crate::secret_manager;
+use super::chat_trait::ChatLlm;
+use super::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, CircuitStatus, Permit};
+use super::health::ModelHealth;
+use super::quota::Quotas;
+use super::rate_limit::RateLimit;
+use super::registry::BackendRegistry;
+use super::{errors::ModelError, ChatRequest, ChatResponse};
+use crate::chat::stream::{with_timeouts, ChatStream};
+use crate::{metrics, secret_manager};
 use anyhow::Context;
+use futures::StreamExt;
 use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ModelsResponse {
     pub models: Vec<String>,
 }
 
+/// Settings any model entry can carry, whatever its backend
+#[derive(Debug, Default, Deserialize)]
+pub struct ModelSettings {
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// How long to wait on the upstream for a whole generation, retries and their waits
+    /// included, or for each token when streaming
+    pub read_timeout_ms: Option<u64>,
+}
+
+impl ModelSettings {
+    fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout_ms.map(Duration::from_millis)
+    }
+}
+
+/// Run `generation`, failing with `Timeout` if it takes longer than `timeout`
+async fn within<T, F>(timeout: Option<Duration>, generation: F) -> Result<T, ModelError>
+where
+    F: std::future::Future<Output = Result<T, ModelError>>,
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, generation)
+            .await
+            .unwrap_or(Err(ModelError::Timeout)),
+        None => generation.await,
+    }
+}
+
+/// The shorter of `timeout` and what is left before `deadline`
+fn time_left(
+    timeout: Option<Duration>,
+    deadline: Option<tokio::time::Instant>,
+) -> Option<Duration> {
+    let left =
+        deadline.map(|deadline| deadline.saturating_duration_since(tokio::time::Instant::now()));
+    match (timeout, left) {
+        (Some(timeout), Some(left)) => Some(timeout.min(left)),
+        (timeout, left) => timeout.or(left),
+    }
+}
+
+/// Whether the caller's `deadline` has passed, which makes a `Timeout` theirs rather than the
+/// upstream's
+fn past(deadline: Option<tokio::time::Instant>) -> bool {
+    deadline.is_some_and(|deadline| tokio::time::Instant::now() >= deadline)
+}
+
+struct LoadedModel {
+    /// Shared with the tasks finishing off streamed generations
+    llm: Arc<dyn ChatLlm + Send + Sync>,
+    /// The `type` of the model's entry
+    backend: String,
+    settings: ModelSettings,
+    breaker: Arc<CircuitBreaker>,
+    /// The entry the model was loaded from, to tell whether a reload changed it
+    entry: serde_json::Value,
+}
+
+/// A virtual model that tries each of `models` in order, moving on when one has an upstream
+/// error or is rate limited
+#[derive(Debug, Deserialize)]
+struct FallbackChain {
+    name: String,
+    models: Vec<String>,
+}
+
 pub struct ChatModels {
-    models: HashMap<String, Box<dyn ChatLlm + Send + Sync>>,
+    models: HashMap<String, LoadedModel>,
+    fallbacks: HashMap<String, Vec<String>>,
+    quotas: Quotas,
 }
 
 impl ChatModels {
-    pub fn new<P: AsRef<Path> + Send + Sync>(models_path: P) -> anyhow::Result<Self> {
-        let mut models: HashMap<String, Box<dyn ChatLlm + Send + Sync>> = HashMap::new();
+    /// Load the models in `model_dir/chat` and the quotas in `model_dir`
+    pub async fn from_model_dir<P: AsRef<Path> + Send + Sync>(
+        model_dir: P,
+    ) -> anyhow::Result<Self> {
+        let mut chat_models = Self::new(model_dir.as_ref().join("chat")).await?;
+        chat_models.quotas = Quotas::load(model_dir.as_ref())?;
+        Ok(chat_models)
+    }
 
-        for file in std::fs::read_dir(&models_path).with_context(|| {
-            format!(
-                "Failed to read directory {} while opening models",
-                models_path.as_ref().display()
-            )
-        })? {
-            let file = file.unwrap();
-            let path = file.path();
-            match path.file_name().and_then(|s| s.to_str()) {
-                Some("mock.json") => {
-                    tracing::info!("Found mock.json, loading mock models");
-                    let mock_models = MockModels::new(&path);
-                    for (name, model) in mock_models.models {
-                        models.insert(name, Box::new(model));
-                    }
-                }
-                Some("reflection.json") => {
-                    tracing::info!("Found reflection.json, loading reflection model");
-                    let reflection_models = ReflectionModels::new(&path);
-                    for (name, model) in reflection_models.models {
-                        models.insert(name, Box::new(model));
+    pub async fn new<P: AsRef<Path> + Send + Sync>(models_path: P) -> anyhow::Result<Self> {
+        Self::with_registry(models_path, &BackendRegistry::default()).await
+    }
+
+    /// Load every config file in `models_path`. Unknown files, unknown model types, duplicate
+    /// model names and fallback chains with missing models are all errors.
+    pub async fn with_registry<P: AsRef<Path> + Send + Sync>(
+        models_path: P,
+        registry: &BackendRegistry,
+    ) -> anyhow::Result<Self> {
+        let mut models: HashMap<String, LoadedModel> = HashMap::new();
+        let mut fallbacks = HashMap::new();
+
+        let mut paths = std::fs::read_dir(&models_path)
+            .with_context(|| {
+                format!(
+                    "Failed to read directory {} while opening models",
+                    models_path.as_ref().display()
+                )
+            })?
+            .map(|file| file.map(|file| file.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.sort();
+
+        for path in paths {
+            let file_name = path
+                .file_name()
+                .and_then(|s| s.to_str())
+                .unwrap_or_default();
+            // Hidden files include the `..data` links of a mounted kubernetes config map
+            if file_name.starts_with('.') {
+                continue;
+            }
+            let entries = registry
+                .entries(&path)
+                .with_context(|| format!("Failed to read models from {}", path.display()))?;
+            tracing::info!("Found {}, loading {} models", file_name, entries.len());
+
+            for entry in entries {
+                if entry["type"] == "fallback" {
+                    let chain: FallbackChain = serde_json::from_value(entry)
+                        .with_context(|| format!("Invalid fallback chain in {}", path.display()))?;
+                    if fallbacks.insert(chain.name.clone(), chain.models).is_some() {
+                        anyhow::bail!("Model {} is defined more than once", chain.name);
                     }
+                    continue;
                 }
-                Some("huggingface.json") => {
-                    tracing::info!("Found huggingface.json, loading huggingface models");
-                    let huggingface_models = HuggingFaceModels::new(&path)?;
-                    for model in huggingface_models.models {
-                        models.insert(model.name.clone(), Box::new(model));
-                    }
+                let settings: ModelSettings = serde_json::from_value(entry.clone())
+                    .with_context(|| format!("Invalid model settings in {}", path.display()))?;
+                let backend = entry["type"].as_str().unwrap_or_default().to_string();
+                let mut llm = registry
+                    .load(entry.clone())
+                    .with_context(|| format!("Failed to load a model from {}", path.display()))?;
+                llm.init().await?;
+                let name = llm.name().to_string();
+                let breaker = Arc::new(CircuitBreaker::new(
+                    settings.circuit_breaker.clone(),
+                    &name,
+                    &backend,
+                ));
+                if models
+                    .insert(
+                        name.clone(),
+                        LoadedModel {
+                            llm: llm.into(),
+                            backend,
+                            settings,
+                            breaker,
+                            entry,
+                        },
+                    )
+                    .is_some()
+                {
+                    anyhow::bail!("Model {} is defined more than once", name);
                 }
-                Some("openai.json") => {
-                    tracing::info!("Found openai.json, loading openai models");
-                    let openai_models = OpenAIModels::new(&path)?;
-                    for model in openai_models.models {
-                        models.insert(model.name.clone(), Box::new(model));
-                    }
+            }
+        }
+
+        for (name, chain) in &fallbacks {
+            if models.contains_key(name) {
+                anyhow::bail!("Model {} is defined more than once", name);
+            }
+            if chain.is_empty() {
+                anyhow::bail!("Fallback chain {} has no models", name);
+            }
+            if let Some(missing) = chain.iter().find(|model| !models.contains_key(*model)) {
+                anyhow::bail!("Fallback chain {} uses unknown model {}", name, missing);
+            }
+        }
+
+        Ok(Self {
+            models,
+            fallbacks,
+            quotas: Quotas::default(),
+        })
+    }
+
+    /// Carry the circuits of the models whose entries are unchanged over from `previous`, so a
+    /// reload doesn't close circuits that are open
+    pub fn keep_circuits(&mut self, previous: &ChatModels) {
+        for (name, model) in self.models.iter_mut() {
+            match previous.models.get(name) {
+                Some(old) if old.entry == model.entry => {
+                    model.breaker = old.breaker.clone();
+                    model.breaker.publish();
                 }
                 _ => {}
             }
         }
+    }
 
-        Ok(Self { models })
+    /// Remove the health and circuit gauges of the models that `next` no longer serves, or serves
+    /// from another backend, so a dropped model doesn't keep reporting its last state
+    pub fn forget_dropped(&self, next: &ChatModels) {
+        for (name, model) in &self.models {
+            if next
+                .models
+                .get(name)
+                .is_some_and(|kept| kept.backend == model.backend)
+            {
+                continue;
+            }
+            let labels = [name.as_str(), model.backend.as_str()];
+            // Only models that were health checked have the health gauge
+            let _ = metrics::MODEL_HEALTHY.remove_label_values(&labels);
+            let _ = metrics::CIRCUIT_STATE.remove_label_values(&labels);
+        }
     }
 
     async fn check_cache(
         &self,
         redis_client: &mut redis::Client,
         uuid: &str,
+        labels: [&str; 2],
     ) -> anyhow::Result<Option<Result<ChatResponse, ModelError>>> {
         let mut redis_connection = redis_client
             .get_async_connection()
@@ -78,6 +248,13 @@ impl ChatModels {
             .get(uuid)
             .await
             .context("Failure to get cached generation")?;
+        let result = match cached_generation {
+            Some(_) => "hit",
+            None => "miss",
+        };
+        metrics::CACHE_LOOKUPS
+            .with_label_values(&[labels[0], labels[1], result])
+            .inc();
 
         Ok(cached_generation.map(|generation| {
             tracing::debug!("Found cached generation for {}", uuid);
@@ -91,7 +268,6 @@ impl ChatModels {
     }
 
     async fn cache_generation(
-        &self,
         redis_client: &mut redis::Client,
         uuid: &str,
         generation: &Result<ChatResponse, ModelError>,
@@ -109,21 +285,220 @@ impl ChatModels {
 
         // If they're looking for this generation after an hour, something has gone wrong
         redis_connection
-            .set_ex(uuid, generation, 60 * 60)
+            .set_ex::<_, _, ()>(uuid, generation, 60 * 60)
             .await
             .context("Failed to set cached generation")?;
         Ok(())
     }
 
+    /// Take the request from the model's rate limits. Without redis the limits aren't enforced,
+    /// and a redis failure lets the request through rather than taking the model down.
+    async fn acquire_rate_limit(
+        model: &LoadedModel,
+        redis_client: &Option<redis::Client>,
+        request: &ChatRequest,
+    ) -> Result<(), ModelError> {
+        let (Some(rate_limit), Some(redis_client)) = (&model.settings.rate_limit, redis_client)
+        else {
+            return Ok(());
+        };
+        let tokens = request.count_tokens(model.llm.as_ref());
+        match rate_limit
+            .acquire(redis_client, &request.model, tokens)
+            .await
+        {
+            Ok(Some(wait)) => {
+                tracing::debug!(
+                    "Rate limit reached for {}, retry in {}ms",
+                    request.model,
+                    wait
+                );
+                Err(ModelError::RateLimitExceeded(wait))
+            }
+            Ok(None) => Ok(()),
+            Err(e) => {
+                tracing::error!("Rate limit error: {:?}", e);
+                Ok(())
+            }
+        }
+    }
+
+    /// Count the request against the caller's quotas, with the same redis rules as the rate
+    /// limits. Returns the windows it was counted in.
+    async fn acquire_quota(
+        &self,
+        model: &LoadedModel,
+        redis_client: &Option<redis::Client>,
+        request: &ChatRequest,
+    ) -> Result<Vec<String>, ModelError> {
+        let Some(redis_client) = redis_client else {
+            return Ok(Vec::new());
+        };
+        let tokens = request.count_tokens(model.llm.as_ref());
+        match self
+            .quotas
+            .acquire(
+                redis_client,
+                request.user.as_deref(),
+                request.team.as_deref(),
+                tokens,
+            )
+            .await
+        {
+            Ok(Err(status)) => {
+                tracing::debug!("Quota exceeded for {}", status.identity);
+                Err(ModelError::QuotaExceeded(status))
+            }
+            Ok(Ok(windows)) => Ok(windows),
+            Err(e) => {
+                tracing::error!("Quota error: {:?}", e);
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// Give back the quota `acquire_quota` took from `windows` for a request that got no
+    /// generation
+    async fn refund_quota(
+        &self,
+        model: &LoadedModel,
+        redis_client: &Option<redis::Client>,
+        request: &ChatRequest,
+        windows: &[String],
+    ) {
+        let Some(redis_client) = redis_client else {
+            return;
+        };
+        let tokens = request.count_tokens(model.llm.as_ref());
+        self.quotas
+            .refund(redis_client, windows, tokens)
+            .await
+            .map_err(|e| tracing::error!("Quota error: {:?}", e))
+            .unwrap_or(());
+    }
+
+    /// Take the generated tokens from the model's rate limit and the caller's quotas
+    async fn charge_usage(
+        rate_limit: Option<RateLimit>,
+        quotas: &Quotas,
+        redis_client: &Option<redis::Client>,
+        model: &str,
+        user: Option<&str>,
+        team: Option<&str>,
+        tokens: usize,
+    ) {
+        let Some(redis_client) = redis_client else {
+            return;
+        };
+        if let Some(rate_limit) = rate_limit {
+            rate_limit
+                .charge(redis_client, model, tokens)
+                .await
+                .map_err(|e| tracing::error!("Rate limit error: {:?}", e))
+                .unwrap_or(());
+        }
+        quotas
+            .charge(redis_client, user, team, tokens)
+            .await
+            .map_err(|e| tracing::error!("Quota error: {:?}", e))
+            .unwrap_or(());
+    }
+
+    /// Metric labels for a model, `unknown` for models that don't exist
+    fn labels(&self, model: &str) -> [&str; 2] {
+        if let Some((name, model)) = self.models.get_key_value(model) {
+            return [name.as_str(), model.backend.as_str()];
+        }
+        match self.fallbacks.get_key_value(model) {
+            Some((name, _)) => [name.as_str(), "fallback"],
+            None => ["unknown", "unknown"],
+        }
+    }
+
+    /// The models that can serve a request for `model`, in the order to try them
+    fn candidates(&self, model: &str) -> Option<Vec<(&str, &LoadedModel)>> {
+        if let Some((name, model)) = self.models.get_key_value(model) {
+            return Some(vec![(name.as_str(), model)]);
+        }
+        let chain = self.fallbacks.get(model)?;
+        Some(
+            chain
+                .iter()
+                .map(|name| (name.as_str(), &self.models[name]))
+                .collect(),
+        )
+    }
+
+    /// Fit the request to a candidate and take it from the candidate's rate limit, failing
+    /// fast while the candidate's circuit is open. A request turned away after the circuit let
+    /// it through gives its probe back.
+    async fn prepare(
+        name: &str,
+        model: &LoadedModel,
+        redis_client: &Option<redis::Client>,
+        request: &ChatRequest,
+    ) -> Result<(ChatRequest, Permit), ModelError> {
+        let Some(permit) = model.breaker.allow() else {
+            tracing::warn!("Circuit for {} is open, not calling the upstream", name);
+            return Err(ModelError::UpstreamModelError);
+        };
+        let labels = [name, model.backend.as_str()];
+        let mut request = request.clone();
+        request.model = name.to_string();
+        let fitted = async {
+            let trimmed = request.trim(model.llm.as_ref())?;
+            metrics::TRIMMED_HISTORY
+                .with_label_values(&labels)
+                .inc_by(trimmed as u64);
+            Self::acquire_rate_limit(model, redis_client, &request).await
+        }
+        .await;
+        if let Err(e) = fitted {
+            permit.release();
+            return Err(e);
+        }
+        metrics::TOKENS
+            .with_label_values(&[labels[0], labels[1], "in"])
+            .inc_by(request.count_tokens(model.llm.as_ref()) as u64);
+        Ok((request, permit))
+    }
+
+    /// Generate a response, giving up with `Timeout` once `deadline` has passed
     pub async fn chat(
+        &self,
+        redis_client: Option<redis::Client>,
+        secret_manager: secret_manager::Secrets,
+        request: ChatRequest,
+        deadline: Option<Duration>,
+    ) -> Result<ChatResponse, ModelError> {
+        let labels = self.labels(&request.model);
+        metrics::REQUESTS.with_label_values(&labels).inc();
+        // A deadline too far off to represent is no deadline
+        let deadline =
+            deadline.and_then(|deadline| tokio::time::Instant::now().checked_add(deadline));
+        let response = self
+            .generate(redis_client, secret_manager, request, deadline)
+            .await;
+        if let Err(e) = &response {
+            metrics::ERRORS
+                .with_label_values(&[labels[0], labels[1], e.kind()])
+                .inc();
+        }
+        response
+    }
+
+    /// Only the upstream calls are bound by `deadline`, so the quota is refunded when it passes
+    async fn generate(
         &self,
         mut redis_client: Option<redis::Client>,
         secret_manager: secret_manager::Secrets,
-        mut request: ChatRequest,
+        request: ChatRequest,
+        deadline: Option<tokio::time::Instant>,
     ) -> Result<ChatResponse, ModelError> {
+        let labels = self.labels(&request.model);
         if let Some(redis_client) = &mut redis_client {
             let cached_generation = self
-                .check_cache(redis_client, &request.uuid)
+                .check_cache(redis_client, &request.uuid, labels)
                 .await
                 .map_err(|e| tracing::error!("Idempotency error: {:?}", e))
                 .unwrap_or(None);
@@ -132,17 +507,96 @@ impl ChatModels {
             }
         }
 
-        let generation = match self.models.get(request.model.as_str()) {
-            Some(model) => {
-                request.trim(model.as_ref())?;
-                model
-                    .chat(
-                        secret_manager,
-                        request.prompt,
-                        request.system,
-                        request.history,
+        // Whether the caller's deadline cut the generation off
+        let mut cut_off = false;
+        let generation = match self.candidates(&request.model) {
+            Some(candidates) => {
+                // Our own limits aren't cached so the same request can be retried once they reset
+                let windows = self
+                    .acquire_quota(candidates[0].1, &redis_client, &request)
+                    .await?;
+                let mut generation = Err(ModelError::ModelNotFound);
+                for (i, (name, model)) in candidates.iter().enumerate() {
+                    let last = i + 1 == candidates.len();
+                    if past(deadline) {
+                        cut_off = true;
+                        generation = Err(ModelError::Timeout);
+                        break;
+                    }
+                    let (attempt, permit) =
+                        match Self::prepare(name, model, &redis_client, &request).await {
+                            Ok(prepared) => prepared,
+                            Err(e) if !last && e.is_transient() => {
+                                tracing::warn!("{} is unavailable ({}), falling back", name, e);
+                                continue;
+                            }
+                            Err(e) => {
+                                self.refund_quota(
+                                    candidates[0].1,
+                                    &redis_client,
+                                    &request,
+                                    &windows,
+                                )
+                                .await;
+                                return Err(e);
+                            }
+                        };
+
+                    let timer = metrics::UPSTREAM_LATENCY
+                        .with_label_values(&[name, &model.backend])
+                        .start_timer();
+                    generation = within(
+                        time_left(model.settings.read_timeout(), deadline),
+                        model.llm.chat_with_details(
+                            secret_manager.clone(),
+                            attempt.prompt,
+                            attempt.system,
+                            attempt.history,
+                        ),
                     )
                     .await
+                    .map(|(generation, details)| (generation, details, name.to_string()));
+                    timer.observe_duration();
+                    // Running out of the caller's own time says nothing about the upstream
+                    cut_off = matches!(generation, Err(ModelError::Timeout)) && past(deadline);
+                    if cut_off {
+                        permit.release();
+                    } else {
+                        permit.record(&generation);
+                    }
+
+                    match &generation {
+                        Ok((generation, details, _)) => {
+                            let tokens = match details {
+                                Some(details) => details.generated_tokens as usize,
+                                None => model.llm.count_tokens(generation),
+                            };
+                            metrics::TOKENS
+                                .with_label_values(&[name, &model.backend, "out"])
+                                .inc_by(tokens as u64);
+                            Self::charge_usage(
+                                model.settings.rate_limit.clone(),
+                                &self.quotas,
+                                &redis_client,
+                                name,
+                                request.user.as_deref(),
+                                request.team.as_deref(),
+                                tokens,
+                            )
+                            .await;
+                            break;
+                        }
+                        Err(e) if !last && e.is_transient() => {
+                            tracing::warn!("{} failed ({}), falling back", name, e);
+                        }
+                        Err(_) => break,
+                    }
+                }
+                if generation.is_err() {
+                    self.refund_quota(candidates[0].1, &redis_client, &request, &windows)
+                        .await;
+                }
+                generation
             }
             None => {
                 tracing::error!("Model not found: {}", request.model);
@@ -150,14 +604,17 @@ impl ChatModels {
             }
         };
         let response = match generation {
-            Ok(generation) => Ok(ChatResponse {
+            Ok((generation, details, model)) => Ok(ChatResponse {
                 generation,
                 uuid: request.uuid.clone(),
+                details,
+                model: Some(model),
             }),
             Err(e) => Err(e),
         };
-        if let Some(redis_client) = &mut redis_client {
-            self.cache_generation(redis_client, &request.uuid, &response)
+        // Like our own limits, the caller's deadline isn't cached so a retry can ask for longer
+        if let (Some(redis_client), false) = (&mut redis_client, cut_off) {
+            Self::cache_generation(redis_client, &request.uuid, &response)
                 .await
                 .map_err(|e| tracing::error!("Failed to cache generation: {:?}", e))
                 .unwrap_or(());
@@ -165,8 +622,310 @@ impl ChatModels {
         response
     }
 
+    /// Streaming counterpart of [`ChatModels::chat`]. Errors that happen before the first
+    /// token are returned directly, and fall back like `chat` does. Later ones end the stream.
+    /// The assembled generation is cached once the upstream finishes, even if the caller has
+    /// gone away, unless it finishes past `deadline`. Returns the model streaming the
+    /// generation, unless it came from the cache. Past `deadline` the stream ends with a
+    /// `Timeout`.
+    pub async fn chat_stream(
+        &self,
+        redis_client: Option<redis::Client>,
+        secret_manager: secret_manager::Secrets,
+        request: ChatRequest,
+        deadline: Option<Duration>,
+    ) -> Result<(Option<String>, ChatStream), ModelError> {
+        let labels = self.labels(&request.model);
+        metrics::REQUESTS.with_label_values(&labels).inc();
+        // A deadline too far off to represent is no deadline
+        let deadline =
+            deadline.and_then(|deadline| tokio::time::Instant::now().checked_add(deadline));
+        let stream = self
+            .generate_stream(redis_client, secret_manager, request, deadline)
+            .await
+            .map(|(model, tokens)| (model, with_timeouts(tokens, None, deadline)));
+        if let Err(e) = &stream {
+            metrics::ERRORS
+                .with_label_values(&[labels[0], labels[1], e.kind()])
+                .inc();
+        }
+        stream
+    }
+
+    async fn generate_stream(
+        &self,
+        mut redis_client: Option<redis::Client>,
+        secret_manager: secret_manager::Secrets,
+        request: ChatRequest,
+        deadline: Option<tokio::time::Instant>,
+    ) -> Result<(Option<String>, ChatStream), ModelError> {
+        let labels = self.labels(&request.model);
+        if let Some(redis_client) = &mut redis_client {
+            let cached_generation = self
+                .check_cache(redis_client, &request.uuid, labels)
+                .await
+                .map_err(|e| tracing::error!("Idempotency error: {:?}", e))
+                .unwrap_or(None);
+            if let Some(generation) = cached_generation {
+                let generation = generation?.generation;
+                let tokens: ChatStream =
+                    Box::pin(futures::stream::once(async move { Ok(generation) }));
+                return Ok((None, tokens));
+            }
+        }
+
+        let mut cut_off = false;
+        let upstream = match self.candidates(&request.model) {
+            Some(candidates) => {
+                let windows = self
+                    .acquire_quota(candidates[0].1, &redis_client, &request)
+                    .await?;
+                let mut upstream = Err(ModelError::ModelNotFound);
+                for (i, (name, model)) in candidates.iter().enumerate() {
+                    let last = i + 1 == candidates.len();
+                    if past(deadline) {
+                        cut_off = true;
+                        upstream = Err(ModelError::Timeout);
+                        break;
+                    }
+                    let (attempt, permit) =
+                        match Self::prepare(name, model, &redis_client, &request).await {
+                            Ok(prepared) => prepared,
+                            Err(e) if !last && e.is_transient() => {
+                                tracing::warn!("{} is unavailable ({}), falling back", name, e);
+                                continue;
+                            }
+                            Err(e) => {
+                                self.refund_quota(
+                                    candidates[0].1,
+                                    &redis_client,
+                                    &request,
+                                    &windows,
+                                )
+                                .await;
+                                return Err(e);
+                            }
+                        };
+
+                    let timer = metrics::UPSTREAM_LATENCY
+                        .with_label_values(&[name, &model.backend])
+                        .start_timer();
+                    let read_timeout = model.settings.read_timeout();
+                    let opened = within(
+                        time_left(read_timeout, deadline),
+                        model.llm.chat_stream(
+                            secret_manager.clone(),
+                            attempt.prompt,
+                            attempt.system,
+                            attempt.history,
+                        ),
+                    )
+                    .await;
+                    cut_off = matches!(opened, Err(ModelError::Timeout)) && past(deadline);
+                    // A stream that opens can still fail, so its permit is recorded once it ends
+                    upstream = match opened {
+                        Ok(tokens) => {
+                            let tokens = with_timeouts(tokens, read_timeout, None);
+                            Ok((tokens, timer, permit, *name, *model))
+                        }
+                        Err(e) if cut_off => {
+                            permit.release();
+                            Err(e)
+                        }
+                        Err(e) => {
+                            permit.record(&Err::<(), _>(e.clone()));
+                            Err(e)
+                        }
+                    };
+                    match &upstream {
+                        Err(e) if !last && e.is_transient() => {
+                            tracing::warn!("{} failed ({}), falling back", name, e);
+                        }
+                        _ => break,
+                    }
+                }
+                if upstream.is_err() {
+                    self.refund_quota(candidates[0].1, &redis_client, &request, &windows)
+                        .await;
+                }
+                upstream
+            }
+            None => {
+                tracing::error!("Model not found: {}", request.model);
+                Err(ModelError::ModelNotFound)
+            }
+        };
+        let (mut upstream, timer, permit, model_name, model) = match upstream {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                if let (Some(redis_client), false) = (&mut redis_client, cut_off) {
+                    Self::cache_generation(redis_client, &request.uuid, &Err(e.clone()))
+                        .await
+                        .map_err(|e| tracing::error!("Failed to cache generation: {:?}", e))
+                        .unwrap_or(());
+                }
+                return Err(e);
+            }
+        };
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(32);
+        let uuid = request.uuid;
+        let (user, team) = (request.user, request.team);
+        let quotas = self.quotas.clone();
+        let rate_limit = model.settings.rate_limit.clone();
+        let backend = model.backend.clone();
+        let llm = model.llm.clone();
+        let model = model_name.to_string();
+        tokio::spawn(async move {
+            let mut generation = String::new();
+            let mut error = None;
+            while let Some(token) = upstream.next().await {
+                match token {
+                    Ok(token) => {
+                        generation.push_str(&token);
+                        // Keep draining the upstream if the caller hangs up so the cache is complete
+                        let _ = sender.send(Ok(token)).await;
+                    }
+                    Err(e) => {
+                        error = Some(e);
+                        break;
+                    }
+                }
+            }
+            timer.observe_duration();
+            match &error {
+                Some(e) => permit.record(&Err::<(), _>(e.clone())),
+                None => permit.record(&Ok::<(), ModelError>(())),
+            }
+            // Stream items can hold any number of tokens, so count the assembled generation the
+            // same way as a generation that isn't streamed
+            let generated_tokens = llm.count_tokens(&generation);
+            metrics::TOKENS
+                .with_label_values(&[&model, &backend, "out"])
+                .inc_by(generated_tokens as u64);
+            Self::charge_usage(
+                rate_limit,
+                &quotas,
+                &redis_client,
+                &model,
+                user.as_deref(),
+                team.as_deref(),
+                generated_tokens,
+            )
+            .await;
+            let response = match error {
+                Some(e) => {
+                    metrics::ERRORS
+                        .with_label_values(&[&model, &backend, e.kind()])
+                        .inc();
+                    Err(e)
+                }
+                None => Ok(ChatResponse {
+                    generation,
+                    uuid: uuid.clone(),
+                    details: None,
+                    model: Some(model.clone()),
+                }),
+            };
+            // The caller was sent a `Timeout` if the generation ran past their deadline
+            if let (Some(redis_client), false) = (&mut redis_client, past(deadline)) {
+                Self::cache_generation(redis_client, &uuid, &response)
+                    .await
+                    .map_err(|e| tracing::error!("Failed to cache generation: {:?}", e))
+                    .unwrap_or(());
+            }
+            if let Err(e) = response {
+                let _ = sender.send(Err(e)).await;
+            }
+        });
+
+        let tokens: ChatStream = Box::pin(tokio_stream::wrappers::ReceiverStream::new(receiver));
+        Ok((Some(model_name.to_string()), tokens))
+    }
+
+    /// Run every model's health check, failing any that take longer than `timeout`
+    pub async fn check_health(
+        &self,
+        secret_manager: secret_manager::Secrets,
+        timeout: Duration,
+    ) -> HashMap<String, ModelHealth> {
+        let checks = self.models.iter().map(|(name, model)| {
+            let secret_manager = secret_manager.clone();
+            async move {
+                let start = Instant::now();
+                let result =
+                    tokio::time::timeout(timeout, model.llm.health_check(secret_manager)).await;
+                let error = match result {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => Some(e.to_string()),
+                    Err(_) => Some("Health check timed out".to_string()),
+                };
+                metrics::MODEL_HEALTHY
+                    .with_label_values(&[name, &model.backend])
+                    .set(error.is_none() as i64);
+                let health = ModelHealth {
+                    healthy: error.is_none(),
+                    checked_at: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|now| now.as_secs())
+                        .unwrap_or_default(),
+                    latency_ms: start.elapsed().as_millis() as u64,
+                    error,
+                };
+                (name.clone(), health)
+            }
+        });
+        let mut health: HashMap<String, ModelHealth> = futures::future::join_all(checks)
+            .await
+            .into_iter()
+            .collect();
+        // A fallback chain is as healthy as its healthiest model
+        for (name, chain) in &self.fallbacks {
+            let chain_health = chain
+                .iter()
+                .filter_map(|model| health.get(model))
+                .find(|health| health.healthy)
+                .or_else(|| chain.first().and_then(|model| health.get(model)))
+                .cloned();
+            if let Some(chain_health) = chain_health {
+                health.insert(name.clone(), chain_health);
+            }
+        }
+        health
+    }
+
+    /// The circuit breaker of every model
+    pub fn circuits(&self) -> HashMap<String, CircuitStatus> {
+        self.models
+            .iter()
+            .map(|(name, model)| (name.clone(), model.breaker.status()))
+            .collect()
+    }
+
+    /// The secrets read by the models that can't be found, as `(model, secret)`
+    pub async fn missing_secrets(
+        &self,
+        secret_manager: &secret_manager::Secrets,
+    ) -> Vec<(String, String)> {
+        let mut missing = Vec::new();
+        for (name, model) in &self.models {
+            for secret in model.llm.secrets() {
+                if secret_manager.get_secret(secret).await.is_none() {
+                    missing.push((name.clone(), secret.to_string()));
+                }
+            }
+        }
+        missing.sort();
+        missing
+    }
+
     pub async fn models(&self) -> Result<ModelsResponse, ModelError> {
-        let models: Vec<String> = self.models.keys().map(|s| s.to_string()).collect();
+        let models: Vec<String> = self
+            .models
+            .keys()
+            .chain(self.fallbacks.keys())
+            .map(|s| s.to_string())
+            .collect();
         Ok(ModelsResponse { models })
     }
 }
@@ -0,0 +1,93 @@
+//! Streaming helpers
+//!
+//! Shared plumbing for backends that can stream their generations token by token.
+
+use crate::chat::errors::ModelError;
+use futures::{Stream, StreamExt};
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A stream of generated tokens. An `Err` item ends the stream.
+pub type ChatStream = Pin<Box<dyn Stream<Item = Result<String, ModelError>> + Send>>;
+
+/// Split a byte stream into lines, dropping the trailing `\n` or `\r\n`.
+pub fn lines<S, B>(stream: S) -> impl Stream<Item = Result<String, ModelError>> + Send
+where
+    S: Stream<Item = reqwest::Result<B>> + Send + 'static,
+    B: AsRef<[u8]>,
+{
+    let state = (Box::pin(stream), Vec::new(), false);
+    futures::stream::unfold(state, |(mut stream, mut buffer, mut done)| async move {
+        loop {
+            if let Some(position) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=position).collect();
+                let line = String::from_utf8_lossy(&line)
+                    .trim_end_matches(['\r', '\n'])
+                    .to_string();
+                return Some((Ok(line), (stream, buffer, done)));
+            }
+            if done {
+                if buffer.is_empty() {
+                    return None;
+                }
+                let line = String::from_utf8_lossy(&buffer)
+                    .trim_end_matches('\r')
+                    .to_string();
+                buffer.clear();
+                return Some((Ok(line), (stream, buffer, done)));
+            }
+            match stream.next().await {
+                Some(Ok(chunk)) => buffer.extend_from_slice(chunk.as_ref()),
+                Some(Err(e)) => {
+                    tracing::error!("Error reading stream from upstream: {}", e);
+                    buffer.clear();
+                    done = true;
+                    return Some((Err(ModelError::UpstreamModelError), (stream, buffer, done)));
+                }
+                None => done = true,
+            }
+        }
+    })
+}
+
+/// Extract the `data:` payloads from a server-sent-event response.
+pub fn sse_data(
+    response: reqwest::Response,
+) -> impl Stream<Item = Result<String, ModelError>> + Send {
+    lines(response.bytes_stream()).filter_map(|line| async move {
+        match line {
+            Ok(line) => line
+                .strip_prefix("data:")
+                .map(|data| Ok(data.trim_start().to_string())),
+            Err(e) => Some(Err(e)),
+        }
+    })
+}
+
+/// End `tokens` with a `Timeout` error when the next token takes longer than `idle` to arrive,
+/// or doesn't arrive before `deadline`.
+pub fn with_timeouts(
+    tokens: ChatStream,
+    idle: Option<Duration>,
+    deadline: Option<Instant>,
+) -> ChatStream {
+    if idle.is_none() && deadline.is_none() {
+        return tokens;
+    }
+    Box::pin(futures::stream::unfold(
+        Some(tokens),
+        move |tokens| async move {
+            let mut tokens = tokens?;
+            let wait = match (idle.map(|idle| Instant::now() + idle), deadline) {
+                (Some(idle), Some(deadline)) => idle.min(deadline),
+                (idle, deadline) => idle.or(deadline)?,
+            };
+            match tokio::time::timeout_at(wait, tokens.next()).await {
+                Ok(Some(token)) => Some((token, Some(tokens))),
+                Ok(None) => None,
+                Err(_) => Some((Err(ModelError::Timeout), None)),
+            }
+        },
+    ))
+}
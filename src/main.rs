@@ -1,8 +1,11 @@
-use axum::{response::IntoResponse, routing::get, Router};
+use axum::{middleware, response::IntoResponse, routing::get, Router};
+pub mod auth;
 pub mod chat;
 pub mod logging;
+pub mod metrics;
 pub mod secret_manager;
 
+use crate::auth::Auth;
 use crate::secret_manager::Secrets;
 
 #[derive(Clone)]
@@ -29,17 +32,30 @@ async fn main() -> anyhow::Result<()> {
     logging::init_logging();
 
     let redis_client = redis_client();
-    let secret_manager = Secrets::from_env();
+    let secret_manager = Secrets::load().await?;
 
     let app_state = AppState {
         redis_client,
         secret_manager,
     };
 
-    let chat_router = chat::chat_router(app_state.clone()).await?;
+    let chat_state = chat::ChatState::load(app_state.clone()).await?;
+
+    // Clients need an API key for the chat routes and the health of each model when any are
+    // configured
+    let mut chat_router = chat::chat_router(chat_state.clone());
+    let mut models_health_router = chat::health::models_health_router(chat_state.clone());
+    if let Some(auth) = Auth::load(&chat::model_dir(), app_state.secret_manager.clone()).await? {
+        let authenticate = middleware::from_fn_with_state(auth, auth::authenticate);
+        chat_router = chat_router.route_layer(authenticate.clone());
+        models_health_router = models_health_router.route_layer(authenticate);
+    }
 
     let app = Router::new()
         .route("/health", get(health))
+        .route("/metrics", get(metrics::metrics))
+        .merge(chat::health::health_router(chat_state))
+        .merge(models_health_router)
         .nest("/chat", chat_router);
 
     let address = &"0.0.0.0:8000".parse().unwrap();
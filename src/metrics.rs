@@ -0,0 +1,124 @@
+//! Prometheus metrics, served on `/metrics`
+//!
+//! Model metrics are labelled with the model name and its backend type. Requests for models
+//! that don't exist are labelled `unknown` so callers can't grow the label set.
+
+use axum::{http::header, response::IntoResponse};
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder,
+    HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+
+pub static REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "llm_router_requests_total",
+        "Chat requests",
+        &["model", "backend"]
+    )
+    .unwrap()
+});
+
+pub static ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "llm_router_errors_total",
+        "Chat requests that failed, by error",
+        &["model", "backend", "error"]
+    )
+    .unwrap()
+});
+
+pub static UPSTREAM_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "llm_router_upstream_latency_seconds",
+        "Time spent waiting on the upstream model, to the end of the stream when streaming",
+        &["model", "backend"],
+        vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0]
+    )
+    .unwrap()
+});
+
+pub static CACHE_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "llm_router_cache_lookups_total",
+        "Idempotency cache lookups, by `hit` or `miss`",
+        &["model", "backend", "result"]
+    )
+    .unwrap()
+});
+
+pub static TRIMMED_HISTORY: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "llm_router_trimmed_history_total",
+        "History items dropped to fit the model's context",
+        &["model", "backend"]
+    )
+    .unwrap()
+});
+
+pub static TOKENS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "llm_router_tokens_total",
+        "Estimated tokens sent to (`in`) and generated by (`out`) the models",
+        &["model", "backend", "direction"]
+    )
+    .unwrap()
+});
+
+pub static MODEL_HEALTHY: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "llm_router_model_healthy",
+        "1 if the model passed its last health check, 0 if it failed",
+        &["model", "backend"]
+    )
+    .unwrap()
+});
+
+pub static CIRCUIT_STATE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "llm_router_circuit_state",
+        "The model's circuit breaker, 0 closed, 1 half open or 2 open",
+        &["model", "backend"]
+    )
+    .unwrap()
+});
+
+pub static MISSING_SECRETS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "llm_router_missing_secrets",
+        "1 for each secret a model reads that couldn't be found when the models were loaded",
+        &["model", "secret"]
+    )
+    .unwrap()
+});
+
+pub static API_KEY_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "llm_router_api_key_requests_total",
+        "Upstream requests sent with each API key, by the secret holding it",
+        &["model", "secret"]
+    )
+    .unwrap()
+});
+
+pub static API_KEY_BENCHED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "llm_router_api_key_benched_total",
+        "Times an API key was rate limited and left out until its `Retry-After`",
+        &["model", "secret"]
+    )
+    .unwrap()
+});
+
+pub async fn metrics() -> impl IntoResponse {
+    tracing::trace!("metrics called");
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!("Failed to encode metrics: {}", e);
+    }
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        buffer,
+    )
+}
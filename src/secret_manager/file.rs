@@ -0,0 +1,52 @@
+//! Secrets mounted as files
+//!
+//! Each key is a file in `SECRETS_DIR`, as Kubernetes mounts a secret into a pod. The file is
+//! read every time the key is needed, so a rotated secret is used as soon as it's remounted.
+
+use super::SecretManager;
+use anyhow::bail;
+use async_trait::async_trait;
+use std::path::PathBuf;
+
+pub struct FileSecretManager {
+    dir: PathBuf,
+}
+
+impl FileSecretManager {
+    pub fn new(dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        if !dir.is_dir() {
+            bail!("Secrets directory {} does not exist", dir.display());
+        }
+        Ok(Self { dir })
+    }
+
+    /// Read secrets from `SECRETS_DIR`, or `None` when it's unset
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        match std::env::var("SECRETS_DIR") {
+            Ok(dir) => {
+                tracing::info!("Reading secrets from {}", dir);
+                Ok(Some(Self::new(dir)?))
+            }
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl SecretManager for FileSecretManager {
+    async fn get_secret(&self, key: &str) -> Option<String> {
+        // Keys name a file in the directory, never a path out of it
+        if key.is_empty() || key.starts_with('.') || key.contains(['/', '\\']) {
+            return None;
+        }
+        match tokio::fs::read_to_string(self.dir.join(key)).await {
+            Ok(secret) => Some(secret.trim_end_matches(['\r', '\n']).to_string()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                tracing::error!("Failed to read secret {}: {}", key, e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+pub mod file;
+pub mod vault;
+
+use file::FileSecretManager;
+use vault::VaultSecretManager;
+
+#[derive(Clone)]
+pub struct Secrets {
+    pub secret_manager: Arc<dyn SecretManager + Send + Sync>,
+}
+
+impl Secrets {
+    pub fn from_env() -> Self {
+        let secret_manager = Arc::new(EnvSecretManager {});
+        Self { secret_manager }
+    }
+
+    /// Read secrets from every configured source: files in `SECRETS_DIR`, then Vault when
+    /// `VAULT_ADDR` is set, then the environment
+    pub async fn load() -> anyhow::Result<Self> {
+        let mut managers: Vec<Arc<dyn SecretManager + Send + Sync>> = Vec::new();
+        if let Some(files) = FileSecretManager::from_env()? {
+            managers.push(Arc::new(files));
+        }
+        if let Some(vault) = VaultSecretManager::from_env().await? {
+            managers.push(vault);
+        }
+        managers.push(Arc::new(EnvSecretManager {}));
+        let secret_manager = Arc::new(SecretChain { managers });
+        Ok(Self { secret_manager })
+    }
+
+    pub async fn get_secret(&self, key: &str) -> Option<String> {
+        self.secret_manager.get_secret(key).await
+    }
+}
+
+#[async_trait]
+pub trait SecretManager {
+    async fn get_secret(&self, key: &str) -> Option<String>;
+}
+
+pub struct EnvSecretManager {}
+
+#[async_trait]
+impl SecretManager for EnvSecretManager {
+    async fn get_secret(&self, key: &str) -> Option<String> {
+        std::env::var(key).ok()
+    }
+}
+
+/// Secret managers tried in order, the first one holding a key wins
+pub struct SecretChain {
+    pub managers: Vec<Arc<dyn SecretManager + Send + Sync>>,
+}
+
+#[async_trait]
+impl SecretManager for SecretChain {
+    async fn get_secret(&self, key: &str) -> Option<String> {
+        for manager in &self.managers {
+            if let Some(secret) = manager.get_secret(key).await {
+                return Some(secret);
+            }
+        }
+        None
+    }
+}
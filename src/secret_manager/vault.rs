@@ -0,0 +1,380 @@
+//! Secrets held in HashiCorp Vault
+//!
+//! Secrets are read from a KV v2 engine. A plain key is a field of the secret at
+//! `VAULT_SECRET_PATH`, and `path#field` is a field of the secret at `path`. Each secret is
+//! cached for `VAULT_CACHE_TTL` seconds, and kept while Vault is unavailable, with a read tried
+//! again every `UNAVAILABLE_RETRY`. A secret that isn't cached is missing until then, as is a
+//! secret Vault denies access to. The router's token is renewed before its lease runs out.
+
+use super::SecretManager;
+use crate::chat::http::{HttpClient, HttpClientConfig};
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+const K8S_TOKEN_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(300);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// The wait before trying again when the token could not be renewed
+const RENEWAL_RETRY: Duration = Duration::from_secs(5);
+/// How long to go without reading a secret again, once Vault failed to answer
+const UNAVAILABLE_RETRY: Duration = Duration::from_secs(30);
+
+/// How the router logs in to Vault
+enum VaultAuth {
+    /// A token given up front
+    Token(String),
+    /// The pod's service account token, exchanged for a Vault token under `role`
+    Kubernetes {
+        role: String,
+        mount: String,
+        jwt_path: String,
+    },
+}
+
+/// A Vault token and how long it lasts
+struct Lease {
+    token: String,
+    renewable: bool,
+    ttl: Duration,
+}
+
+#[derive(Deserialize)]
+struct AuthResponse {
+    auth: Auth,
+}
+
+#[derive(Deserialize)]
+struct Auth {
+    client_token: String,
+    lease_duration: u64,
+    renewable: bool,
+}
+
+impl From<Auth> for Lease {
+    fn from(auth: Auth) -> Self {
+        Self {
+            token: auth.client_token,
+            renewable: auth.renewable,
+            ttl: Duration::from_secs(auth.lease_duration),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LookupResponse {
+    data: Lookup,
+}
+
+#[derive(Deserialize)]
+struct Lookup {
+    ttl: u64,
+    #[serde(default)]
+    renewable: bool,
+}
+
+#[derive(Deserialize)]
+struct SecretResponse {
+    data: SecretVersion,
+}
+
+#[derive(Deserialize)]
+struct SecretVersion {
+    data: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum VaultError {
+    /// The token was refused, even after logging in again
+    #[error("Vault denied access to {0}")]
+    Denied(String),
+    #[error(transparent)]
+    Unavailable(#[from] anyhow::Error),
+}
+
+struct CachedSecret {
+    fields: HashMap<String, String>,
+    /// When to read the secret again
+    fresh_until: Instant,
+}
+
+pub struct VaultSecretManager {
+    address: String,
+    namespace: Option<String>,
+    auth: VaultAuth,
+    kv_mount: String,
+    path: String,
+    cache_ttl: Duration,
+    http: HttpClient,
+    token: RwLock<String>,
+    /// Secrets by path. Each path is locked while it's read, so an expired secret is only read
+    /// once, without holding up the other paths.
+    cache: Mutex<HashMap<String, Arc<tokio::sync::Mutex<Option<CachedSecret>>>>>,
+}
+
+impl VaultSecretManager {
+    /// Log in to the Vault at `VAULT_ADDR`, or `None` when it's unset
+    pub async fn from_env() -> anyhow::Result<Option<Arc<Self>>> {
+        let Ok(address) = std::env::var("VAULT_ADDR") else {
+            return Ok(None);
+        };
+        let var = |name: &str, default: &str| std::env::var(name).unwrap_or(default.to_string());
+        let auth = match (
+            std::env::var("VAULT_TOKEN"),
+            std::env::var("VAULT_K8S_ROLE"),
+        ) {
+            (Ok(token), _) => VaultAuth::Token(token),
+            (Err(_), Ok(role)) => VaultAuth::Kubernetes {
+                role,
+                mount: var("VAULT_K8S_MOUNT", "kubernetes"),
+                jwt_path: var("VAULT_K8S_TOKEN_PATH", K8S_TOKEN_PATH),
+            },
+            _ => bail!("Set VAULT_TOKEN or VAULT_K8S_ROLE to log in to Vault"),
+        };
+        let cache_ttl = match std::env::var("VAULT_CACHE_TTL") {
+            Ok(seconds) => Duration::from_secs(
+                seconds
+                    .parse()
+                    .map_err(|_| anyhow!("VAULT_CACHE_TTL must be a number of seconds"))?,
+            ),
+            Err(_) => DEFAULT_CACHE_TTL,
+        };
+        let http = HttpClient::try_from(HttpClientConfig {
+            ca_bundle: std::env::var("VAULT_CACERT").ok(),
+            ..Default::default()
+        })
+        .map_err(|e| anyhow!(e))?;
+
+        let vault = Arc::new(Self {
+            address: address.trim_end_matches('/').to_string(),
+            namespace: std::env::var("VAULT_NAMESPACE").ok(),
+            auth,
+            kv_mount: var("VAULT_KV_MOUNT", "secret"),
+            path: var("VAULT_SECRET_PATH", "llm_router"),
+            cache_ttl,
+            http,
+            token: RwLock::new(String::new()),
+            cache: Default::default(),
+        });
+        let lease = vault
+            .login()
+            .await
+            .with_context(|| format!("Failed to log in to Vault at {}", address))?;
+        tracing::info!("Reading secrets from Vault at {}", address);
+        vault.clone().keep_renewed(lease);
+        Ok(Some(vault))
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let mut request = self
+            .http
+            .request(method, format!("{}/v1/{}", self.address, path))
+            .timeout(REQUEST_TIMEOUT);
+        if let Some(namespace) = &self.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+        request
+    }
+
+    fn token(&self) -> String {
+        self.token.read().unwrap().clone()
+    }
+
+    async fn call<T: DeserializeOwned>(&self, request: RequestBuilder) -> anyhow::Result<T> {
+        let response = request.send().await?.error_for_status()?;
+        Ok(response.json().await?)
+    }
+
+    /// Get a new token, or for token auth look up how long the given one lasts
+    async fn login(&self) -> anyhow::Result<Lease> {
+        let lease = match &self.auth {
+            VaultAuth::Token(token) => {
+                let lookup: LookupResponse = self
+                    .call(
+                        self.request(Method::GET, "auth/token/lookup-self")
+                            .header("X-Vault-Token", token),
+                    )
+                    .await?;
+                Lease {
+                    token: token.clone(),
+                    renewable: lookup.data.renewable,
+                    ttl: Duration::from_secs(lookup.data.ttl),
+                }
+            }
+            VaultAuth::Kubernetes {
+                role,
+                mount,
+                jwt_path,
+            } => {
+                let jwt = std::fs::read_to_string(jwt_path).with_context(|| {
+                    format!("Failed to read the service account token {}", jwt_path)
+                })?;
+                let login: AuthResponse = self
+                    .call(
+                        self.request(Method::POST, &format!("auth/{}/login", mount))
+                            .json(&serde_json::json!({"role": role, "jwt": jwt.trim()})),
+                    )
+                    .await?;
+                login.auth.into()
+            }
+        };
+        *self.token.write().unwrap() = lease.token.clone();
+        Ok(lease)
+    }
+
+    async fn renew(&self) -> anyhow::Result<Lease> {
+        let renewal: AuthResponse = self
+            .call(
+                self.request(Method::POST, "auth/token/renew-self")
+                    .header("X-Vault-Token", self.token())
+                    .json(&serde_json::json!({})),
+            )
+            .await?;
+        let lease: Lease = renewal.auth.into();
+        *self.token.write().unwrap() = lease.token.clone();
+        Ok(lease)
+    }
+
+    /// Renew the token halfway through each lease, logging in again when it can't be renewed
+    fn keep_renewed(self: Arc<Self>, lease: Lease) {
+        tokio::spawn(async move {
+            let mut renewable = lease.renewable;
+            // Tokens without a TTL never expire
+            let mut wait = lease.ttl / 2;
+            while !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+                let renewed = match renewable {
+                    true => self.renew().await,
+                    false => Err(anyhow!("the token is not renewable")),
+                };
+                let renewed = match renewed {
+                    Ok(lease) => Ok(lease),
+                    Err(e) => {
+                        tracing::warn!(
+                            "Failed to renew the Vault token ({:#}), logging in again",
+                            e
+                        );
+                        self.login().await
+                    }
+                };
+                match renewed {
+                    Ok(lease) => {
+                        tracing::debug!("Vault token renewed for {:?}", lease.ttl);
+                        renewable = lease.renewable;
+                        wait = (lease.ttl / 2).max(Duration::from_secs(1));
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to log in to Vault: {:#}", e);
+                        wait = RENEWAL_RETRY;
+                    }
+                }
+            }
+        });
+    }
+
+    async fn read(&self, path: &str) -> anyhow::Result<reqwest::Response> {
+        Ok(self
+            .request(Method::GET, &format!("{}/data/{}", self.kv_mount, path))
+            .header("X-Vault-Token", self.token())
+            .send()
+            .await?)
+    }
+
+    /// Read the fields of the secret at `path`, logging in again once if the token is refused
+    async fn fetch(&self, path: &str) -> Result<HashMap<String, String>, VaultError> {
+        let mut response = self.read(path).await?;
+        if response.status() == StatusCode::FORBIDDEN {
+            self.login()
+                .await
+                .map_err(|_| VaultError::Denied(path.to_string()))?;
+            response = self.read(path).await?;
+        }
+        match response.status() {
+            StatusCode::FORBIDDEN => return Err(VaultError::Denied(path.to_string())),
+            StatusCode::NOT_FOUND => return Ok(HashMap::new()),
+            _ => {}
+        }
+        let secret: SecretResponse = response
+            .error_for_status()
+            .map_err(anyhow::Error::from)?
+            .json()
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(secret
+            .data
+            .data
+            .into_iter()
+            .map(|(field, value)| match value {
+                serde_json::Value::String(value) => (field, value),
+                value => (field, value.to_string()),
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl SecretManager for VaultSecretManager {
+    async fn get_secret(&self, key: &str) -> Option<String> {
+        let (path, field) = key.split_once('#').unwrap_or((&self.path, key));
+        let entry = self
+            .cache
+            .lock()
+            .unwrap()
+            .entry(path.to_string())
+            .or_default()
+            .clone();
+        let mut cached = entry.lock().await;
+        if let Some(cached) = cached.as_ref() {
+            if Instant::now() < cached.fresh_until {
+                return cached.fields.get(field).cloned();
+            }
+        }
+        match self.fetch(path).await {
+            Ok(fields) => {
+                let value = fields.get(field).cloned();
+                let fresh_until = Instant::now() + self.cache_ttl;
+                *cached = Some(CachedSecret {
+                    fields,
+                    fresh_until,
+                });
+                value
+            }
+            // Keys outlive an outage, but not losing access to them. A path with nothing cached
+            // waits out the outage too, so each lookup doesn't wait on Vault before the next
+            // secret manager.
+            Err(VaultError::Unavailable(e)) => {
+                tracing::warn!(
+                    "Vault is unavailable ({:#}), reading {} again in {}s",
+                    e,
+                    path,
+                    UNAVAILABLE_RETRY.as_secs()
+                );
+                let cached = cached.get_or_insert_with(|| CachedSecret {
+                    fields: HashMap::new(),
+                    fresh_until: Instant::now(),
+                });
+                cached.fresh_until = Instant::now() + UNAVAILABLE_RETRY;
+                cached.fields.get(field).cloned()
+            }
+            // A denied path is dropped, and the denial is cached so each lookup doesn't ask Vault
+            // again until the next retry.
+            Err(e @ VaultError::Denied(_)) => {
+                tracing::error!(
+                    "Failed to read {} from Vault: {:#}, reading it again in {}s",
+                    path,
+                    e,
+                    UNAVAILABLE_RETRY.as_secs()
+                );
+                *cached = Some(CachedSecret {
+                    fields: HashMap::new(),
+                    fresh_until: Instant::now() + UNAVAILABLE_RETRY,
+                });
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+import requests
+from uuid import uuid4
+
+from .test_mock import read_events
+
+url = "http://llm_router:8000"
+
+
+def test_generate_anthropic():
+    history = [
+        {"prompt": "first", "generation": "stub: 1 messages, first"},
+        {"prompt": "second", "generation": "stub: 3 messages, second"},
+    ]
+    payload = {
+        "uuid": str(uuid4()),
+        "prompt": "third",
+        "system": "test",
+        "model": "claude-3-haiku",
+        "history": history,
+    }
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"] == "stub: 5 messages, third"
+
+
+def test_generate_anthropic_rate_limit():
+    """The upstream's Retry-After is passed on"""
+    payload = {"uuid": str(uuid4()), "prompt": "rate_limit", "model": "claude-3-haiku"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 429
+    assert response.headers["Retry-After"] == "7"
+
+
+def test_generate_anthropic_overloaded():
+    """Anthropic's 529 is an upstream error"""
+    payload = {"uuid": str(uuid4()), "prompt": "overloaded", "model": "claude-3-haiku"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 500
+    assert response.json()["error"] == "Upstream model error"
+
+
+def test_generate_anthropic_stream():
+    payload = {"uuid": str(uuid4()), "prompt": "hello there", "model": "claude-3-haiku"}
+    response = requests.post(url + "/chat/generate_stream", json=payload, stream=True)
+    assert response.status_code == 200
+    events = read_events(response)
+    tokens = [data["token"] for event, data in events if event == "message"]
+    assert len(tokens) > 1
+    assert "".join(tokens) == "stub: 1 messages, hello there"
+
+
+def test_generate_anthropic_stream_error():
+    """An error event in the stream ends it with an error"""
+    payload = {"uuid": str(uuid4()), "prompt": "stream_error", "model": "claude-3-haiku"}
+    response = requests.post(url + "/chat/generate_stream", json=payload, stream=True)
+    assert response.status_code == 200
+    events = read_events(response)
+    assert events[0][0] == "message"
+    assert events[-1][0] == "error"
@@ -0,0 +1,64 @@
+import requests
+from uuid import uuid4
+
+from .test_metrics import metric
+
+url = "http://llm_router:8000"
+
+
+def test_rate_limited_key_is_benched():
+    """The model has a key the stub always rate limits, which is benched after its first 429
+    while the other key answers"""
+    for _ in range(4):
+        payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-openai-keys"}
+        response = requests.post(url + "/chat/generate", json=payload)
+        assert response.status_code == 200
+        assert response.json()["generation"] == "stub: 1 messages, hello"
+
+    benched = metric("llm_router_api_key_benched_total", model="stub-openai-keys", secret="LIMITED_API_TOKEN")
+    assert benched == 1
+    limited = metric("llm_router_api_key_requests_total", model="stub-openai-keys", secret="LIMITED_API_TOKEN")
+    assert limited == 1
+    working = metric("llm_router_api_key_requests_total", model="stub-openai-keys", secret="STUB_API_TOKEN")
+    assert working >= 4
+
+
+def test_missing_key_is_skipped():
+    """One of the model's keys isn't in any secret, so every request goes out with the other"""
+    for _ in range(4):
+        payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-openai-missing-key"}
+        response = requests.post(url + "/chat/generate", json=payload)
+        assert response.status_code == 200
+
+    missing = metric("llm_router_api_key_requests_total", model="stub-openai-missing-key", secret="MISSING_API_TOKEN")
+    assert missing == 0
+    working = metric("llm_router_api_key_requests_total", model="stub-openai-missing-key", secret="STUB_API_TOKEN")
+    assert working == 4
+
+
+def test_all_keys_rate_limited():
+    """The model's only key is rate limited, so the upstream's Retry-After is passed on"""
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-openai-limited-key"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 429
+    assert response.headers["Retry-After"] == "60"
+
+
+def test_all_keys_missing():
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-openai-no-keys"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 500
+    assert response.json()["error"] == "Other error"
+
+
+def test_benched_key_and_missing_keys():
+    """Once the only key there is gets benched, requests fail instead of going out without a key"""
+    model = "stub-openai-limited-missing-keys"
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": model}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 429
+
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": model}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 500
+    assert response.json()["error"] == "Other error"
@@ -0,0 +1,114 @@
+import requests
+from uuid import uuid4
+
+from .test_mock import read_events
+
+# A router with clients configured, so every chat request needs an API key
+url = "http://llm_router_auth:8000"
+CTFD_KEY = "ctfd-key"
+GRADER_KEY = "grader-key"
+OPS_KEY = "ops-key"
+ADMIN_KEY = "admin-key"
+
+
+def generate(model, key=None, stream=False):
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": model}
+    headers = {"Authorization": f"Bearer {key}"} if key else {}
+    path = "/chat/generate_stream" if stream else "/chat/generate"
+    return requests.post(url + path, json=payload, headers=headers, stream=stream)
+
+
+def test_missing_key():
+    response = generate("mock_model")
+    assert response.status_code == 401
+    assert response.json() == {"error": "Missing API key"}
+    assert response.headers["WWW-Authenticate"] == "Bearer"
+
+
+def test_invalid_key():
+    response = generate("mock_model", key="not-a-key")
+    assert response.status_code == 401
+    assert response.json() == {"error": "Invalid API key"}
+
+
+def test_client_with_every_model():
+    assert generate("mock_model", key=CTFD_KEY).status_code == 200
+    assert generate("other_mock_model", key=CTFD_KEY).status_code == 200
+
+
+def test_client_with_allowed_models():
+    assert generate("mock_model", key=GRADER_KEY).status_code == 200
+    response = generate("other_mock_model", key=GRADER_KEY)
+    assert response.status_code == 403
+    assert response.json() == {"error": "Not allowed to use model other_mock_model"}
+
+
+def test_stream_with_allowed_models():
+    response = generate("mock_model", key=GRADER_KEY, stream=True)
+    assert response.status_code == 200
+    assert read_events(response)[-1][0] == "done"
+    assert generate("other_mock_model", key=GRADER_KEY, stream=True).status_code == 403
+
+
+def test_models_listed_for_client():
+    response = requests.get(url + "/chat/models", headers={"Authorization": f"Bearer {GRADER_KEY}"})
+    assert response.status_code == 200
+    assert response.json()["models"] == ["mock_model"]
+    assert requests.get(url + "/chat/models").status_code == 401
+
+
+def test_health_without_key():
+    assert requests.get(url + "/health").status_code == 200
+    assert requests.get(url + "/health/ready").status_code == 200
+    assert requests.get(url + "/metrics").status_code == 200
+
+
+def test_models_health_for_client():
+    """The health of each model needs a key, and only lists the models the client may use"""
+    for path in ["/health/models", "/health/circuits"]:
+        assert requests.get(url + path).status_code == 401
+        response = requests.get(url + path, headers={"Authorization": f"Bearer {GRADER_KEY}"})
+        assert response.status_code == 200
+        assert list(response.json()["models"]) == ["mock_model"]
+        response = requests.get(url + path, headers={"Authorization": f"Bearer {CTFD_KEY}"})
+        assert "other_mock_model" in response.json()["models"]
+
+
+def generate_for(key, **identity):
+    payload = {"uuid": str(uuid4()), "prompt": "test", "model": "mock_model", **identity}
+    return requests.post(url + "/chat/generate", json=payload, headers={"Authorization": f"Bearer {key}"})
+
+
+def test_client_is_its_own_team():
+    assert generate_for(GRADER_KEY, team="grader").status_code == 200
+    response = generate_for(GRADER_KEY, team="ctfd")
+    assert response.status_code == 403
+    assert response.json() == {"error": "Not allowed to act for team ctfd"}
+    assert generate_for(ADMIN_KEY, team="ctfd").status_code == 200
+
+
+def test_client_users_are_kept_apart():
+    """A user's daily tokens run out for one client, but the same name is a new user for another"""
+    user = str(uuid4())
+    responses = [generate_for(GRADER_KEY, user=user) for _ in range(5)]
+    limited = [r for r in responses if r.status_code == 429]
+    assert limited
+    assert limited[0].json()["quota"]["identity"] == f"user:grader/{user}"
+    assert generate_for(CTFD_KEY, user=user).status_code == 200
+
+
+def reload(key):
+    return requests.post(url + "/chat/reload", headers={"Authorization": f"Bearer {key}"})
+
+
+def test_reload_by_admin_client():
+    response = reload(CTFD_KEY)
+    assert response.status_code == 403
+    assert response.json() == {"error": "Not allowed to reload models"}
+    assert reload(OPS_KEY).status_code == 200
+    assert reload(ADMIN_KEY).status_code == 200
+    assert requests.post(url + "/chat/reload").status_code == 401
+
+
+def test_admin_key_uses_every_model():
+    assert generate("other_mock_model", key=ADMIN_KEY).status_code == 200
@@ -0,0 +1,37 @@
+import requests
+from uuid import uuid4
+
+from .test_mock import read_events
+
+url = "http://llm_router:8000"
+
+
+def test_generate_azure():
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "system": "test", "model": "stub-azure"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"] == "stub: 2 messages, hello"
+
+
+def test_generate_azure_content_filter():
+    payload = {"uuid": str(uuid4()), "prompt": "content_filter", "model": "stub-azure"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 422
+    assert response.json()["error"] == "Content filtered"
+
+
+def test_generate_azure_rate_limit():
+    """The upstream's Retry-After is passed on"""
+    payload = {"uuid": str(uuid4()), "prompt": "rate_limit", "model": "stub-azure"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 429
+    assert response.headers["Retry-After"] == "7"
+
+
+def test_generate_azure_stream():
+    payload = {"uuid": str(uuid4()), "prompt": "hello there", "model": "stub-azure"}
+    response = requests.post(url + "/chat/generate_stream", json=payload, stream=True)
+    assert response.status_code == 200
+    events = read_events(response)
+    tokens = "".join(data["token"] for event, data in events if event == "message")
+    assert tokens == "stub: 1 messages, hello there"
@@ -0,0 +1,76 @@
+import requests
+import time
+from uuid import uuid4
+
+from .test_mock import read_events
+
+url = "http://llm_router:8000"
+
+
+def circuit(model):
+    response = requests.get(url + "/health/circuits")
+    assert response.status_code == 200
+    return response.json()["models"][model]
+
+
+def test_circuit_closed():
+    assert circuit("mock_model") == {"state": "closed", "recent_failures": 0}
+
+
+def test_circuit_opens_after_failures():
+    """stub-tgi-down can't be reached, its circuit opens after two failures"""
+    for _ in range(2):
+        payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-tgi-down"}
+        response = requests.post(url + "/chat/generate", json=payload)
+        assert response.status_code == 500
+    assert circuit("stub-tgi-down") == {"state": "open", "recent_failures": 2}
+
+    # Open circuits fail fast, without adding to the failures
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-tgi-down"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 500
+    assert circuit("stub-tgi-down")["recent_failures"] == 2
+
+    metrics = requests.get(url + "/metrics").text
+    assert 'llm_router_circuit_state{backend="tgi",model="stub-tgi-down"} 2' in metrics
+
+
+def test_probe_cut_off_by_deadline():
+    """A probe cut off by the caller's own deadline says nothing about the upstream, so it is
+    given back instead of counting as a failure"""
+    model = "stub-openai-slow-probe"
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": model}
+    assert requests.post(url + "/chat/generate", json=payload).status_code == 504
+    assert circuit(model) == {"state": "open", "recent_failures": 1}
+
+    time.sleep(1.5)
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": model}
+    response = requests.post(url + "/chat/generate", json=payload, headers={"X-Request-Timeout": "0.3"})
+    assert response.status_code == 504
+    assert circuit(model) == {"state": "open", "recent_failures": 1}
+
+    # The next request probes straight away, and its read timeout is a failure
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": model}
+    assert requests.post(url + "/chat/generate", json=payload).status_code == 504
+    assert circuit(model) == {"state": "open", "recent_failures": 2}
+
+
+def test_deadline_not_counted():
+    """Callers can't open a circuit for everyone else by asking for short deadlines"""
+    for _ in range(5):
+        payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-openai-slow"}
+        response = requests.post(url + "/chat/generate", json=payload, headers={"X-Request-Timeout": "0.001"})
+        assert response.status_code == 504
+    assert circuit("stub-openai-slow") == {"state": "closed", "recent_failures": 0}
+
+
+def test_stream_failing_midway():
+    """The stream opens, then stalls past the read timeout, which counts against the circuit"""
+    model = "stub-openai-stall"
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": model}
+    response = requests.post(url + "/chat/generate_stream", json=payload, stream=True)
+    assert response.status_code == 200
+    events = read_events(response)
+    assert events[0][0] == "message"
+    assert events[-1][0] == "error"
+    assert circuit(model) == {"state": "open", "recent_failures": 1}
@@ -0,0 +1,40 @@
+import requests
+from uuid import uuid4
+
+from .test_mock import read_events
+
+url = "http://llm_router:8000"
+
+
+def test_generate_cohere():
+    history = [
+        {"prompt": "first", "generation": "stub: 2 messages, first"},
+        {"prompt": "second", "generation": "stub: 4 messages, second"},
+    ]
+    payload = {
+        "uuid": str(uuid4()),
+        "prompt": "third",
+        "system": "test",
+        "model": "command-r",
+        "history": history,
+    }
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"] == "stub: 6 messages, third"
+
+
+def test_generate_cohere_rate_limit():
+    payload = {"uuid": str(uuid4()), "prompt": "rate_limit", "model": "command-r"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 429
+    assert response.headers["Retry-After"] == "7"
+
+
+def test_generate_cohere_stream():
+    payload = {"uuid": str(uuid4()), "prompt": "hello there", "model": "command-r"}
+    response = requests.post(url + "/chat/generate_stream", json=payload, stream=True)
+    assert response.status_code == 200
+    events = read_events(response)
+    tokens = [data["token"] for event, data in events if event == "message"]
+    assert len(tokens) > 1
+    assert "".join(tokens) == "stub: 1 messages, hello there"
@@ -0,0 +1,32 @@
+import requests
+from uuid import uuid4
+
+url = "http://llm_router:8000"
+
+
+def test_yaml_models_listed():
+    """Models from a yaml config with a `type` on each entry are served"""
+    response = requests.get(url + "/chat/models")
+    assert response.status_code == 200
+    models = set(response.json()["models"])
+    assert {"yaml_mock_model", "yaml_reflection_model"}.issubset(models)
+
+
+def test_yaml_mock_model():
+    uuid = str(uuid4())
+    payload = {"uuid": uuid, "prompt": "test", "system": "test", "model": "yaml_mock_model"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"] == "response: 0, This is a valid response from the yaml mock model"
+
+
+def test_reload():
+    """Reloading an unchanged config keeps serving the same models"""
+    before = set(requests.get(url + "/chat/models").json()["models"])
+    response = requests.post(url + "/chat/reload", headers={"Authorization": "Bearer admin-key"})
+    assert response.status_code == 200
+    assert set(response.json()["models"]) == before
+
+    payload = {"uuid": str(uuid4()), "prompt": "test", "system": "test", "model": "yaml_mock_model"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
@@ -0,0 +1,30 @@
+import requests
+from uuid import uuid4
+
+from .test_mock import read_events
+
+url = "http://llm_router:8000"
+
+
+def test_fallback_listed():
+    response = requests.get(url + "/chat/models")
+    assert response.status_code == 200
+    assert "yaml_fallback_model" in response.json()["models"]
+
+
+def test_fallback_generate():
+    """The first model in the chain fails upstream, so the mock model answers"""
+    payload = {"uuid": str(uuid4()), "prompt": "test", "system": "test", "model": "yaml_fallback_model"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"] == "response: 0, This is a valid response from the yaml mock model"
+    assert response.json()["model"] == "yaml_mock_model"
+
+
+def test_fallback_generate_stream():
+    payload = {"uuid": str(uuid4()), "prompt": "test", "system": "test", "model": "yaml_fallback_model"}
+    response = requests.post(url + "/chat/generate_stream", json=payload, stream=True)
+    assert response.status_code == 200
+    events = read_events(response)
+    assert events[-1][0] == "done"
+    assert events[-1][1]["model"] == "yaml_mock_model"
@@ -0,0 +1,48 @@
+import requests
+from uuid import uuid4
+
+from .test_mock import read_events
+
+url = "http://llm_router:8000"
+
+
+def test_generate_gemini():
+    history = [
+        {"prompt": "first", "generation": "stub: 1 contents, first"},
+        {"prompt": "second", "generation": "stub: 3 contents, second"},
+    ]
+    payload = {
+        "uuid": str(uuid4()),
+        "prompt": "third",
+        "system": "test",
+        "model": "gemini-1.5-flash",
+        "history": history,
+    }
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"] == "stub: 5 contents, third"
+
+
+def test_generate_gemini_blocked():
+    payload = {"uuid": str(uuid4()), "prompt": "blocked", "model": "gemini-1.5-flash"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 422
+    assert response.json()["error"] == "Content filtered"
+
+
+def test_generate_gemini_rate_limit():
+    """The upstream's Retry-After is passed on"""
+    payload = {"uuid": str(uuid4()), "prompt": "rate_limit", "model": "gemini-1.5-flash"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 429
+    assert response.headers["Retry-After"] == "7"
+
+
+def test_generate_gemini_stream():
+    payload = {"uuid": str(uuid4()), "prompt": "hello there", "model": "gemini-1.5-flash"}
+    response = requests.post(url + "/chat/generate_stream", json=payload, stream=True)
+    assert response.status_code == 200
+    events = read_events(response)
+    tokens = [data["token"] for event, data in events if event == "message"]
+    assert len(tokens) > 1
+    assert "".join(tokens) == "stub: 1 contents, hello there"
@@ -0,0 +1,69 @@
+import requests
+import time
+from uuid import uuid4
+
+url = "http://llm_router:8000"
+
+
+def models_health():
+    """The health of every model, once the first round of checks is done"""
+    for _ in range(50):
+        response = requests.get(url + "/health/models")
+        assert response.status_code == 200
+        models = response.json()["models"]
+        if all(health is not None for health in models.values()):
+            return models
+        time.sleep(0.2)
+    raise AssertionError(f"Models were never checked: {models}")
+
+
+def test_health_models():
+    """Every model is listed, and the stubbed upstreams pass their checks"""
+    health = models_health()
+    models = requests.get(url + "/chat/models").json()["models"]
+    assert set(models).issubset(health.keys())
+    for model in ["mock_model", "stub-tgi", "stub-ollama", "stub-openai", "stub-openai-open", "command-r"]:
+        assert health[model]["healthy"], health[model]
+
+
+def test_health_models_unhealthy():
+    """The stub turns away requests without the tenant header"""
+    health = models_health()["stub-openai-no-tenant"]
+    assert not health["healthy"]
+    assert health["error"]
+
+
+def test_hide_unhealthy():
+    """The router runs with MODEL_HIDE_UNHEALTHY set"""
+    models_health()
+    models = requests.get(url + "/chat/models").json()["models"]
+    assert "stub-openai-no-tenant" not in models
+    assert "mock_model" in models
+
+
+def test_ready():
+    response = requests.get(url + "/health/ready")
+    assert response.status_code == 200
+
+
+def test_reload_lists_visible_models():
+    """A reload answers with the models on /chat/models, while every model stays on /health/models"""
+    health = models_health()
+    response = requests.post(url + "/chat/reload", headers={"Authorization": "Bearer admin-key"})
+    assert response.status_code == 200
+    reloaded = set(response.json()["models"])
+    assert reloaded == set(requests.get(url + "/chat/models").json()["models"])
+    assert "stub-openai-no-tenant" not in reloaded
+    assert "stub-openai-no-tenant" in health
+
+
+def test_health_checks_spend_no_tokens():
+    """Paid backends are checked by looking their model up, not by generating. The stub can
+    generate with command-r-unlisted, but doesn't list it."""
+    health = models_health()
+    for model in ["command-r", "stub-azure"]:
+        assert health[model]["healthy"], health[model]
+    assert not health["command-r-unlisted"]["healthy"]
+    assert "404" in health["command-r-unlisted"]["error"]
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "command-r-unlisted"}
+    assert requests.post(url + "/chat/generate", json=payload).status_code == 200
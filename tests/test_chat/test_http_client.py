@@ -0,0 +1,20 @@
+import requests
+from uuid import uuid4
+
+url = "http://llm_router:8000"
+
+
+def test_default_user_agent():
+    """The stub answers with the User-Agent it was sent"""
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-openai-user-agent"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"].startswith("llm_router/")
+
+
+def test_custom_client():
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-openai-custom-client"}
+    for _ in range(3):
+        response = requests.post(url + "/chat/generate", json={**payload, "uuid": str(uuid4())})
+        assert response.status_code == 200
+        assert response.json()["generation"] == "ctf-router"
@@ -0,0 +1,65 @@
+import math
+import requests
+from uuid import uuid4
+
+from .test_mock import read_events
+
+url = "http://llm_router:8000"
+
+
+def metric(name, **labels):
+    """Sum of the samples of a metric with the given labels"""
+    response = requests.get(url + "/metrics")
+    assert response.status_code == 200
+    total = 0.0
+    for line in response.text.splitlines():
+        if not line.startswith(name + "{"):
+            continue
+        sample_labels, value = line[len(name) + 1:].rsplit("} ", 1)
+        sample_labels = dict(label.split("=", 1) for label in sample_labels.split(","))
+        if all(sample_labels.get(k) == f'"{v}"' for k, v in labels.items()):
+            total += float(value)
+    return total
+
+
+def test_metrics_count_requests_and_errors():
+    requests_before = metric("llm_router_requests_total", model="mock_model", backend="mock")
+    errors_before = metric("llm_router_errors_total", model="mock_model", error="other")
+
+    payload = {"uuid": str(uuid4()), "prompt": "test", "system": "test", "model": "mock_model"}
+    assert requests.post(url + "/chat/generate", json=payload).status_code == 200
+    payload = {"uuid": str(uuid4()), "prompt": "error", "system": "test", "model": "mock_model"}
+    assert requests.post(url + "/chat/generate", json=payload).status_code == 500
+
+    assert metric("llm_router_requests_total", model="mock_model", backend="mock") == requests_before + 2
+    assert metric("llm_router_errors_total", model="mock_model", error="other") == errors_before + 1
+    assert metric("llm_router_tokens_total", model="mock_model", direction="out") > 0
+    assert metric("llm_router_upstream_latency_seconds_count", model="mock_model") > 0
+
+
+def test_metrics_unknown_model():
+    before = metric("llm_router_errors_total", model="unknown", error="model_not_found")
+    payload = {"uuid": str(uuid4()), "prompt": "test", "model": str(uuid4())}
+    assert requests.post(url + "/chat/generate", json=payload).status_code == 404
+    assert metric("llm_router_errors_total", model="unknown", error="model_not_found") == before + 1
+
+
+def test_metrics_trimmed_history():
+    before = metric("llm_router_trimmed_history_total", model="mock_model")
+    history = [{"prompt": "test " * 20, "generation": "test " * 20}] * 20
+    payload = {"uuid": str(uuid4()), "prompt": "test", "system": "test", "model": "mock_model", "history": history}
+    assert requests.post(url + "/chat/generate", json=payload).status_code == 200
+    assert metric("llm_router_trimmed_history_total", model="mock_model") > before
+
+
+def test_metrics_streamed_tokens():
+    """A stream is counted by the tokens in its generation, however many items it came in"""
+    before = metric("llm_router_tokens_total", model="mock_model", direction="out")
+    payload = {"uuid": str(uuid4()), "prompt": "test", "system": "test", "model": "mock_model"}
+    response = requests.post(url + "/chat/generate_stream", json=payload, stream=True)
+    assert response.status_code == 200
+    event, done = read_events(response)[-1]
+    assert event == "done"
+    tokens = math.ceil(len(done["generation"].split()) * 1.35)
+    assert tokens > 1
+    assert metric("llm_router_tokens_total", model="mock_model", direction="out") == before + tokens
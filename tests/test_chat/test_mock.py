@@ -56,6 +56,21 @@ def test_generate_long():
     assert response.status_code == 422
 
 
+def test_generate_content_filtered():
+    uuid = str(uuid4())
+    payload = {
+        "uuid": uuid,
+        "prompt": "content_filtered",
+        "system": "test",
+        "model": "mock_model",
+    }
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 422
+    assert response.json()["error"] == "Content filtered"
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 422
+
+
 def test_generate_long_response():
     uuid = str(uuid4())
     payload = {
@@ -111,3 +126,45 @@ def test_generate_chat():
     assert response.status_code == 200
     assert response.json()["generation"].startswith("response: 1,")
     assert response.json()["generation"].endswith(short_response)
+
+
+def read_events(response):
+    """Parses a server-sent-event response into a list of (event, data) pairs"""
+    events = []
+    event, data = "message", []
+    for line in response.iter_lines(decode_unicode=True):
+        if not line:
+            if data:
+                events.append((event, json.loads("\n".join(data))))
+            event, data = "message", []
+        elif line.startswith("event:"):
+            event = line[len("event:"):].strip()
+        elif line.startswith("data:"):
+            data.append(line[len("data:"):].strip())
+    return events
+
+
+def test_generate_stream():
+    uuid = str(uuid4())
+    payload = {"uuid": uuid, "prompt": "test", "system": "test", "model": "mock_model"}
+    response = requests.post(url + "/chat/generate_stream", json=payload, stream=True)
+    assert response.status_code == 200
+    events = read_events(response)
+    tokens = "".join(data["token"] for event, data in events if event == "message")
+    assert tokens == f"response: 0, {short_response}"
+    assert events[-1][0] == "done"
+    assert events[-1][1]["generation"] == tokens
+
+    # The assembled generation is cached for the non-streaming endpoint too
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"] == tokens
+
+
+def test_generate_stream_error():
+    uuid = str(uuid4())
+    payload = {"uuid": uuid, "prompt": "error", "system": "test", "model": "mock_model"}
+    response = requests.post(url + "/chat/generate_stream", json=payload, stream=True)
+    assert response.status_code == 500
+    response = requests.post(url + "/chat/generate_stream", json=payload, stream=True)
+    assert response.status_code == 500
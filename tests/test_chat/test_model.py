@@ -41,3 +41,13 @@ def test_generate_huggingface():
 @pytest.mark.external
 def test_generate_openai():
     generate_for_model("gpt-3.5-turbo")
+
+
+@pytest.mark.external
+def test_generate_anthropic():
+    generate_for_model("claude-3-haiku")
+
+
+@pytest.mark.external
+def test_generate_gemini():
+    generate_for_model("gemini-1.5-flash")
@@ -0,0 +1,38 @@
+import requests
+from uuid import uuid4
+
+from .test_mock import read_events
+
+url = "http://llm_router:8000"
+
+
+def test_generate_ollama():
+    history = [{"prompt": "first", "generation": "stub: 1 messages, first"}]
+    payload = {
+        "uuid": str(uuid4()),
+        "prompt": "second",
+        "system": "test",
+        "model": "stub-ollama",
+        "history": history,
+    }
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"] == "stub: 4 messages, second"
+
+
+def test_generate_ollama_rate_limit():
+    """A busy server is a rate limit rather than an upstream error"""
+    payload = {"uuid": str(uuid4()), "prompt": "rate_limit", "model": "stub-ollama"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 429
+    assert response.headers["Retry-After"] == "7"
+
+
+def test_generate_ollama_stream():
+    payload = {"uuid": str(uuid4()), "prompt": "hello there", "model": "stub-ollama"}
+    response = requests.post(url + "/chat/generate_stream", json=payload, stream=True)
+    assert response.status_code == 200
+    events = read_events(response)
+    tokens = [data["token"] for event, data in events if event == "message"]
+    assert len(tokens) > 1
+    assert "".join(tokens) == "stub: 1 messages, hello there"
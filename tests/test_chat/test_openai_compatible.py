@@ -0,0 +1,52 @@
+import requests
+from uuid import uuid4
+
+from .test_mock import read_events
+
+url = "http://llm_router:8000"
+
+
+def test_generate_openai_compatible():
+    """The stub echoes the message count and the last prompt"""
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "system": "test", "model": "stub-openai"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"] == "stub: 2 messages, hello"
+
+
+def test_generate_openai_compatible_history():
+    history = [{"prompt": "first", "generation": "stub: 1 messages, first"}]
+    payload = {
+        "uuid": str(uuid4()),
+        "prompt": "second",
+        "system": "test",
+        "model": "stub-openai",
+        "history": history,
+    }
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"] == "stub: 4 messages, second"
+
+
+def test_generate_openai_compatible_without_auth():
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-openai-open"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"] == "stub: 1 messages, hello"
+
+
+def test_generate_openai_compatible_missing_header():
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-openai-no-tenant"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 500
+
+
+def test_generate_openai_compatible_stream():
+    payload = {"uuid": str(uuid4()), "prompt": "hello there", "model": "stub-openai"}
+    response = requests.post(url + "/chat/generate_stream", json=payload, stream=True)
+    assert response.status_code == 200
+    events = read_events(response)
+    tokens = [data["token"] for event, data in events if event == "message"]
+    assert len(tokens) > 1
+    assert "".join(tokens) == "stub: 1 messages, hello there"
+    assert events[-1] == ("done", {"generation": "".join(tokens), "uuid": payload["uuid"], "model": "stub-openai"})
@@ -0,0 +1,56 @@
+import requests
+import time
+from uuid import uuid4
+
+url = "http://llm_router:8000"
+
+
+def generate(prompt="test", **identity):
+    payload = {"uuid": str(uuid4()), "prompt": prompt, "system": "test", "model": "mock_model", **identity}
+    return requests.post(url + "/chat/generate", json=payload)
+
+
+def test_team_hourly_requests():
+    """A team gets two requests an hour, the third reports the quota it ran out of"""
+    team = str(uuid4())
+    responses = [generate(team=team) for _ in range(3)]
+    assert [r.status_code for r in responses[:2]] == [200, 200]
+    assert responses[2].status_code == 429
+
+    body = responses[2].json()
+    assert body["error"] == "Quota exceeded"
+    assert body["quota"]["identity"] == f"team:{team}"
+    assert body["quota"]["window"] == "hourly"
+    assert body["quota"]["requests_remaining"] == 0
+    assert time.time() < body["quota"]["resets_at"] <= time.time() + 60 * 60
+    assert 0 < int(responses[2].headers["Retry-After"]) <= 60 * 60
+
+
+def test_user_daily_tokens():
+    """Generated tokens count towards the user's daily token quota"""
+    user = str(uuid4())
+    responses = [generate(user=user) for _ in range(5)]
+    limited = [r for r in responses if r.status_code == 429]
+    assert limited
+    assert limited[0].json()["quota"]["window"] == "daily"
+    assert limited[0].json()["quota"]["tokens_remaining"] < 40
+
+
+def test_failed_requests_are_refunded():
+    """Requests the upstream fails don't use up the team's two requests an hour"""
+    team = str(uuid4())
+    assert all(generate(prompt="error", team=team).status_code == 500 for _ in range(3))
+    assert [generate(team=team).status_code for _ in range(2)] == [200, 200]
+
+
+def test_timed_out_requests_are_refunded():
+    """Requests past their deadline don't use up the team's two requests an hour"""
+    team = str(uuid4())
+    payload = {"uuid": str(uuid4()), "prompt": "slow", "system": "test", "model": "mock_model", "team": team}
+    headers = {"X-Request-Timeout": "0.5"}
+    assert requests.post(url + "/chat/generate", json=payload, headers=headers).status_code == 504
+    assert [generate(team=team).status_code for _ in range(2)] == [200, 200]
+
+
+def test_no_identity_has_no_quota():
+    assert all(generate().status_code == 200 for _ in range(3))
@@ -0,0 +1,25 @@
+import requests
+from uuid import uuid4
+
+url = "http://llm_router:8000"
+
+
+def generate(model):
+    payload = {"uuid": str(uuid4()), "prompt": "test", "system": "test", "model": model}
+    return requests.post(url + "/chat/generate", json=payload)
+
+
+def test_requests_per_minute():
+    """The third request in a minute to a model limited to two is turned away"""
+    responses = [generate("yaml_limited_model") for _ in range(3)]
+    assert [r.status_code for r in responses[:2]] == [200, 200]
+    assert responses[2].status_code == 429
+    assert responses[2].json()["error"] == "Rate limit exceeded"
+    # Two requests a minute refill one every 30 seconds
+    assert 0 < int(responses[2].headers["Retry-After"]) <= 30
+
+
+def test_rate_limit_is_per_model():
+    for _ in range(3):
+        generate("yaml_limited_model")
+    assert generate("yaml_mock_model").status_code == 200
@@ -0,0 +1,154 @@
+import requests
+from pathlib import Path
+from uuid import uuid4
+
+# A router reading its config from a directory shared with the tests, so they can change it
+url = "http://llm_router_reload:8000"
+model_dir = Path("reload_models")
+ADMIN_KEY = "admin-key"
+
+
+def reload(key=ADMIN_KEY):
+    headers = {"Authorization": f"Bearer {key}"} if key else {}
+    return requests.post(url + "/chat/reload", headers=headers)
+
+
+def models():
+    response = requests.get(url + "/chat/models")
+    assert response.status_code == 200
+    return set(response.json()["models"])
+
+
+def generate(model):
+    payload = {"uuid": str(uuid4()), "prompt": "test", "system": "test", "model": model}
+    return requests.post(url + "/chat/generate", json=payload)
+
+
+def test_reload_needs_admin_key():
+    response = reload(key=None)
+    assert response.status_code == 401
+    assert response.headers["WWW-Authenticate"] == "Bearer"
+    assert reload(key="not-the-admin-key").status_code == 401
+
+
+def test_reload_new_model():
+    path = model_dir / "chat" / "added.yaml"
+    path.write_text("- type: mock\n  name: added_mock_model\n  short: Added\n  long: Added\n")
+    try:
+        response = reload()
+        assert response.status_code == 200
+        assert "added_mock_model" in response.json()["models"]
+        assert generate("added_mock_model").status_code == 200
+    finally:
+        path.unlink()
+        assert reload().status_code == 200
+    assert "added_mock_model" not in models()
+
+
+def test_reload_broken_config():
+    """A config that fails to load is rejected, and the models already in service are kept"""
+    before = models()
+    assert "reload_mock_model" in before
+    path = model_dir / "chat" / "broken.yaml"
+    path.write_text("- type: not_a_backend\n  name: broken_model\n")
+    try:
+        response = reload()
+        assert response.status_code == 422
+        # The reason is only logged, so nothing about the config reaches the caller
+        assert response.json() == {"error": "Failed to reload models, keeping the current set"}
+        assert models() == before
+        assert generate("reload_mock_model").status_code == 200
+    finally:
+        path.unlink()
+        assert reload().status_code == 200
+
+
+def circuit(model):
+    response = requests.get(url + "/health/circuits")
+    assert response.status_code == 200
+    return response.json()["models"][model]
+
+
+def test_reload_keeps_open_circuits():
+    """A reload leaves the circuit of a model it doesn't change open, and closes the circuit of
+    one it does"""
+    path = model_dir / "chat" / "down.yaml"
+    down = (
+        "- type: openai\n  name: down_model\n  model: stub\n  parameters: {{}}\n  context_size: 2048\n"
+        "  base_url: http://upstream_stub:9/v1\n  api_key_secret: null\n"
+        "  circuit_breaker:\n    failures: 1\n    open_seconds: {open_seconds}\n"
+    )
+    path.write_text(down.format(open_seconds=300))
+    try:
+        assert reload().status_code == 200
+        assert generate("down_model").status_code == 500
+        assert circuit("down_model")["state"] == "open"
+
+        added = model_dir / "chat" / "added.yaml"
+        added.write_text("- type: mock\n  name: added_mock_model\n  short: Added\n  long: Added\n")
+        try:
+            assert reload().status_code == 200
+            assert circuit("down_model")["state"] == "open"
+        finally:
+            added.unlink()
+
+        path.write_text(down.format(open_seconds=600))
+        assert reload().status_code == 200
+        assert circuit("down_model")["state"] == "closed"
+    finally:
+        path.unlink()
+        assert reload().status_code == 200
+
+
+def openai_model(name, api_key_secret):
+    return (
+        f"- type: openai\n  name: {name}\n  model: stub\n  parameters: {{}}\n  context_size: 2048\n"
+        f"  base_url: http://upstream_stub:8080/v1\n  api_key_secret: {api_key_secret}\n"
+    )
+
+
+def test_reload_requires_secrets():
+    """The router runs with MODEL_REQUIRE_SECRETS=true, so a model naming a secret that isn't
+    there is rejected along with the rest of the config"""
+    before = models()
+    path = model_dir / "chat" / "secrets.yaml"
+    path.write_text(openai_model("missing_secret_model", "RELOAD_MISSING_API_KEY"))
+    try:
+        response = reload()
+        assert response.status_code == 422
+        assert models() == before
+    finally:
+        path.unlink()
+        assert reload().status_code == 200
+
+
+def test_reload_with_secrets():
+    path = model_dir / "chat" / "secrets.yaml"
+    path.write_text(openai_model("secret_model", "RELOAD_API_KEY"))
+    try:
+        response = reload()
+        assert response.status_code == 200
+        assert "secret_model" in response.json()["models"]
+    finally:
+        path.unlink()
+        assert reload().status_code == 200
+
+
+def circuit_gauges():
+    response = requests.get(url + "/metrics")
+    assert response.status_code == 200
+    return [line for line in response.text.splitlines() if line.startswith("llm_router_circuit_state{")]
+
+
+def test_reload_drops_gauges():
+    """The gauges of a model a reload drops go with it"""
+    path = model_dir / "chat" / "added.yaml"
+    path.write_text("- type: mock\n  name: added_mock_model\n  short: Added\n  long: Added\n")
+    try:
+        assert reload().status_code == 200
+        assert any('model="added_mock_model"' in line for line in circuit_gauges())
+    finally:
+        path.unlink()
+        assert reload().status_code == 200
+    assert not any('model="added_mock_model"' in line for line in circuit_gauges())
+    assert any('model="reload_mock_model"' in line for line in circuit_gauges())
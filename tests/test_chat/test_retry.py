@@ -0,0 +1,25 @@
+import requests
+import time
+from uuid import uuid4
+
+url = "http://llm_router:8000"
+
+
+def test_retry_until_success():
+    """The stub fails twice, once with a Retry-After of a second, before answering"""
+    prompt = str(uuid4())
+    payload = {"uuid": str(uuid4()), "prompt": prompt, "model": "stub-openai-flaky"}
+    started = time.monotonic()
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"] == f"stub: 1 messages, {prompt}"
+    assert time.monotonic() - started >= 1
+
+
+def test_retry_respects_deadline():
+    """Waiting out the Retry-After would pass the deadline, so the 503 is returned"""
+    payload = {"uuid": str(uuid4()), "prompt": str(uuid4()), "model": "stub-openai-flaky-deadline"}
+    started = time.monotonic()
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 500
+    assert time.monotonic() - started < 1
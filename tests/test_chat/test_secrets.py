@@ -0,0 +1,51 @@
+import os
+import requests
+from pathlib import Path
+from uuid import uuid4
+
+url = "http://llm_router:8000"
+# Shared with the router, which reads it as its SECRETS_DIR
+secrets_dir = Path("secrets")
+
+
+def write_secret(key, value):
+    """Replace the file in one step, as Kubernetes does when it updates a mounted secret"""
+    secrets_dir.mkdir(exist_ok=True)
+    path = secrets_dir / key
+    tmp = secrets_dir / f".{key}.tmp"
+    tmp.write_text(value + "\n")
+    os.replace(tmp, path)
+
+
+def generate(model):
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": model}
+    return requests.post(url + "/chat/generate", json=payload)
+
+
+def test_file_overrides_vault():
+    """The stub Vault holds a wrong FILE_API_TOKEN, which the file takes precedence over"""
+    (secrets_dir / "FILE_API_TOKEN").unlink(missing_ok=True)
+    assert generate("stub-openai-file").status_code != 200
+    write_secret("FILE_API_TOKEN", "stub-token")
+    assert generate("stub-openai-file").status_code == 200
+
+
+def test_rotated_file():
+    write_secret("FILE_API_TOKEN", "rotated-token")
+    assert generate("stub-openai-file").status_code != 200
+    write_secret("FILE_API_TOKEN", "stub-token")
+    assert generate("stub-openai-file").status_code == 200
+
+
+def test_env_fallback():
+    response = generate("stub-openai-env")
+    assert response.status_code == 200
+
+
+def test_missing_secrets_reported():
+    """Models naming secrets that aren't anywhere still load, and the secrets are listed"""
+    response = requests.get(url + "/metrics")
+    assert response.status_code == 200
+    assert 'llm_router_missing_secrets{model="stub-openai-no-keys",secret="MISSING_API_TOKEN"} 1' in response.text
+    assert 'llm_router_missing_secrets{model="stub-openai-missing-key",secret="MISSING_API_TOKEN"} 1' in response.text
+    assert 'llm_router_missing_secrets{model="stub-openai-missing-key",secret="STUB_API_TOKEN"}' not in response.text
@@ -0,0 +1,77 @@
+import requests
+from uuid import uuid4
+
+from .test_mock import read_events
+
+url = "http://llm_router:8000"
+
+
+def test_generate_tgi_details():
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "system": "test", "model": "stub-tgi"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"] == "stub: 1 prompts"
+    assert response.json()["details"] == {"finish_reason": "eos_token", "generated_tokens": 3}
+
+
+def test_generate_tgi_context_from_info():
+    """The stub reports a small max_input_length, so long histories get trimmed"""
+    history = [{"prompt": "tell me more", "generation": "here is more"} for _ in range(10)]
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-tgi", "history": history}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    prompts = int(response.json()["generation"].split(" ")[1])
+    assert 1 < prompts < 11
+
+
+def test_generate_tgi_input_too_long():
+    payload = {"uuid": str(uuid4()), "prompt": "too long", "model": "stub-tgi"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 422
+    assert response.json()["error"] == "Prompt too long"
+
+
+def test_generate_tgi_invalid_parameters():
+    """Only inputs TGI finds too long are reported as such, other rejections are upstream errors"""
+    payload = {"uuid": str(uuid4()), "prompt": "bad parameter", "model": "stub-tgi"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 500
+    assert response.json()["error"] == "Upstream model error"
+
+
+def test_generate_tgi_overloaded():
+    payload = {"uuid": str(uuid4()), "prompt": "overloaded", "model": "stub-tgi"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 429
+    assert response.headers["Retry-After"] == "7"
+
+
+def test_generate_tgi_stream():
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-tgi"}
+    response = requests.post(url + "/chat/generate_stream", json=payload, stream=True)
+    assert response.status_code == 200
+    events = read_events(response)
+    tokens = "".join(data["token"] for event, data in events if event == "message")
+    assert tokens == "stub: 1 prompts"
+    assert events[-1][0] == "done"
+
+
+def test_generate_tgi_replicas():
+    """One replica is down, it is ejected after its first failure and the other serves the rest"""
+    payload = {"prompt": "hello", "system": "test", "model": "stub-tgi-replicas"}
+    statuses = [
+        requests.post(url + "/chat/generate", json={**payload, "uuid": str(uuid4())}).status_code
+        for _ in range(6)
+    ]
+    assert statuses.count(500) <= 1
+    assert statuses[-3:] == [200, 200, 200]
+
+
+def test_generate_tgi_replicas_context_from_info():
+    """The first replica is down, so the context size is read from the next one's /info"""
+    history = [{"prompt": "tell me more", "generation": "here is more"} for _ in range(10)]
+    payload = {"prompt": "hello", "model": "stub-tgi-replicas", "history": history}
+    response = requests.post(url + "/chat/generate", json={**payload, "uuid": str(uuid4())})
+    assert response.status_code == 200
+    prompts = int(response.json()["generation"].split(" ")[1])
+    assert 1 < prompts < 11
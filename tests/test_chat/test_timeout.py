@@ -0,0 +1,81 @@
+import requests
+import time
+from uuid import uuid4
+
+url = "http://llm_router:8000"
+
+
+def test_slow_model_without_timeouts():
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-openai-slow"}
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"] == "stub: 1 messages, hello"
+
+
+def test_read_timeout():
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-openai-slow-read-timeout"}
+    started = time.monotonic()
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 504
+    assert response.json()["error"] == "Request timed out"
+    assert time.monotonic() - started < 2
+
+
+def test_attempt_timeout():
+    """Each of the two attempts gives up on the stub after half a second"""
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-openai-slow-attempts"}
+    started = time.monotonic()
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 500
+    assert response.json()["error"] == "Upstream model error"
+    assert 1 <= time.monotonic() - started < 2
+
+
+def test_request_timeout_header():
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-openai-slow"}
+    started = time.monotonic()
+    response = requests.post(url + "/chat/generate", json=payload, headers={"X-Request-Timeout": "0.5"})
+    assert response.status_code == 504
+    assert time.monotonic() - started < 2
+
+
+def test_request_timeout_header_stream():
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-openai-slow"}
+    response = requests.post(
+        url + "/chat/generate_stream", json=payload, headers={"X-Request-Timeout": "0.5"}, stream=True
+    )
+    assert response.status_code == 504
+
+
+def test_request_timeout_not_cached():
+    """A retry of the same request with a longer deadline reaches the model again"""
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-openai-slow"}
+    response = requests.post(url + "/chat/generate", json=payload, headers={"X-Request-Timeout": "0.5"})
+    assert response.status_code == 504
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"] == "stub: 1 messages, hello"
+
+
+def test_request_timeout_stream_not_cached():
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "stub-openai-slow"}
+    response = requests.post(
+        url + "/chat/generate_stream", json=payload, headers={"X-Request-Timeout": "0.5"}, stream=True
+    )
+    assert response.status_code == 504
+    response = requests.post(url + "/chat/generate", json=payload)
+    assert response.status_code == 200
+    assert response.json()["generation"] == "stub: 1 messages, hello"
+
+
+def test_invalid_request_timeout_header():
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "mock_model"}
+    response = requests.post(url + "/chat/generate", json=payload, headers={"X-Request-Timeout": "soon"})
+    assert response.status_code == 400
+
+
+def test_huge_request_timeout_header():
+    """A timeout too long to add to the clock is cut down rather than failing the request"""
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": "mock_model"}
+    response = requests.post(url + "/chat/generate", json=payload, headers={"X-Request-Timeout": "1e19"})
+    assert response.status_code == 200
@@ -0,0 +1,30 @@
+import requests
+import time
+from uuid import uuid4
+
+url = "http://llm_router:8000"
+
+
+def generate(model):
+    payload = {"uuid": str(uuid4()), "prompt": "hello", "model": model}
+    return requests.post(url + "/chat/generate", json=payload)
+
+
+def test_secret_from_vault():
+    """The stub's token is only held in the stub Vault"""
+    response = generate("stub-openai")
+    assert response.status_code == 200
+    assert response.json()["generation"] == "stub: 1 messages, hello"
+
+
+def test_secret_at_path():
+    response = generate("stub-openai-vault-path")
+    assert response.status_code == 200
+
+
+def test_token_renewed():
+    """Secrets are cached for a second and the stub's tokens last two, so they are read again
+    with a renewed token"""
+    time.sleep(3)
+    response = generate("stub-openai")
+    assert response.status_code == 200
@@ -0,0 +1,468 @@
+"""A stand-in for upstream model providers so the router's backends can be tested without keys"""
+import json
+import secrets
+import time
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+from urllib.parse import parse_qs, urlparse
+
+STUB_TOKEN = "stub-token"
+# Model listings that don't check the token
+OPEN_MODELS_PATHS = ("/open/v1/models", "/flaky/v1/models", "/slow/v1/models", "/stall/v1/models", "/agent/v1/models", "/keys/v1/models")
+# A key that is always over its rate limit
+LIMITED_TOKEN = "limited-token"
+TGI_MAX_INPUT_LENGTH = 30
+# How long the slow endpoint takes to answer
+SLOW_SECONDS = 2
+# Attempts seen by the flaky endpoint, per prompt
+flaky_attempts = {}
+# Vault logins are for the service account token mounted into the router
+VAULT_ROLE = "llm_router"
+VAULT_JWT = "stub-jwt"
+# Short enough that the router has to renew its token during the tests
+VAULT_TOKEN_TTL = 2
+# Expiry time of every Vault token handed out
+vault_tokens = {}
+# KV v2 secrets by path
+vault_secrets = {
+    "llm_router": {
+        "STUB_API_TOKEN": STUB_TOKEN,
+        # Overridden by a mounted file
+        "FILE_API_TOKEN": "vault-token",
+        "LIMITED_API_TOKEN": LIMITED_TOKEN,
+    },
+    "stub": {"api_key": STUB_TOKEN},
+}
+
+
+def tokenize(generation):
+    words = generation.split(" ")
+    return [words[0]] + [" " + word for word in words[1:]]
+
+
+def tgi_generation(request):
+    return f"stub: {request['inputs'].count('<|user|>')} prompts"
+
+
+def cohere_generation(request):
+    preamble = 1 if request.get("preamble") else 0
+    messages = preamble + len(request["chat_history"]) + 1
+    return f"stub: {messages} messages, {request['message']}"
+
+
+def gemini_generation(request):
+    contents = request["contents"]
+    return f"stub: {len(contents)} contents, {contents[-1]['parts'][0]['text']}"
+
+
+def anthropic_generation(request):
+    messages = request["messages"]
+    return f"stub: {len(messages)} messages, {messages[-1]['content']}"
+
+
+def openai_generation(request):
+    messages = request["messages"]
+    return f"stub: {len(messages)} messages, {messages[-1]['content']}"
+
+
+class StubHandler(BaseHTTPRequestHandler):
+    protocol_version = "HTTP/1.1"
+
+    def send_json(self, status, body, headers=None):
+        payload = json.dumps(body).encode()
+        self.send_response(status)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(payload)))
+        for header, value in (headers or {}).items():
+            self.send_header(header, value)
+        self.end_headers()
+        self.wfile.write(payload)
+
+    def send_events(self, events):
+        self.send_response(200)
+        self.send_header("Content-Type", "text/event-stream")
+        self.send_header("Connection", "close")
+        self.end_headers()
+        for event in events:
+            self.wfile.write(f"data: {event}\n\n".encode())
+            self.wfile.flush()
+        self.close_connection = True
+
+    def read_json(self):
+        length = int(self.headers.get("Content-Length", 0))
+        return json.loads(self.rfile.read(length) or b"{}")
+
+    def do_GET(self):
+        if self.path == "/health":
+            return self.send_json(200, {"status": "ok"})
+        if self.path == "/tgi/info":
+            return self.send_json(
+                200,
+                {
+                    "model_id": "stub/tgi",
+                    "max_input_length": TGI_MAX_INPUT_LENGTH,
+                    "max_total_tokens": TGI_MAX_INPUT_LENGTH + 16,
+                },
+            )
+        if self.path == "/tgi/health":
+            return self.send_json(200, {})
+        if self.path.startswith("/v1/secret/data/"):
+            return self.vault_read(self.path.removeprefix("/v1/secret/data/"))
+        if self.path == "/v1/auth/token/lookup-self":
+            return self.vault_lookup()
+        if self.path == "/ollama/api/tags":
+            return self.send_json(200, {"models": [{"name": "stub:latest"}]})
+        if self.path.startswith("/cohere/v1/models/"):
+            return self.cohere_model(self.path.removeprefix("/cohere/v1/models/"))
+        if self.path.startswith("/anthropic/v1/models/"):
+            return self.anthropic_model(self.path.removeprefix("/anthropic/v1/models/"))
+        if self.path.startswith("/gemini/v1beta/models/"):
+            return self.gemini_model(self.path.removeprefix("/gemini/v1beta/models/"))
+        if urlparse(self.path).path == "/azure/openai/models":
+            return self.azure_models()
+        if self.path == "/v1/models":
+            return self.openai_models(authenticated=True)
+        if self.path in OPEN_MODELS_PATHS:
+            return self.openai_models(authenticated=False)
+        self.send_json(404, {"error": "not found"})
+
+    def do_POST(self):
+        request = self.read_json()
+        if self.path == "/v1/auth/kubernetes/login":
+            return self.vault_login(request)
+        if self.path == "/v1/auth/token/renew-self":
+            return self.vault_renew()
+        if self.path.startswith("/azure/openai/deployments/"):
+            return self.azure_chat(request)
+        if self.path == "/v1/chat/completions":
+            return self.openai_chat(request, authenticated=True)
+        if self.path == "/open/v1/chat/completions":
+            return self.openai_chat(request, authenticated=False)
+        if self.path == "/keys/v1/chat/completions":
+            return self.keys_chat(request)
+        if self.path == "/flaky/v1/chat/completions":
+            return self.flaky_chat(request)
+        if self.path == "/slow/v1/chat/completions":
+            time.sleep(SLOW_SECONDS)
+            return self.openai_chat(request, authenticated=False)
+        if self.path == "/stall/v1/chat/completions":
+            return self.stall_chat(request)
+        if self.path == "/agent/v1/chat/completions":
+            return self.openai_chat(request, authenticated=False, generation=self.headers.get("User-Agent"))
+        if self.path == "/cohere/v1/chat":
+            return self.cohere_chat(request)
+        if self.path == "/anthropic/v1/messages":
+            return self.anthropic_messages(request)
+        if self.path.startswith("/gemini/v1beta/models/"):
+            return self.gemini_generate(request)
+        if self.path == "/ollama/api/chat":
+            return self.ollama_chat(request)
+        if self.path == "/tgi/generate":
+            return self.tgi_generate(request)
+        if self.path == "/tgi/generate_stream":
+            return self.tgi_generate_stream(request)
+        self.send_json(404, {"error": "not found"})
+
+    def vault_token(self):
+        """The request's Vault token if it hasn't expired"""
+        token = self.headers.get("X-Vault-Token")
+        if vault_tokens.get(token, 0) < time.time():
+            return None
+        return token
+
+    def vault_auth(self, token):
+        vault_tokens[token] = time.time() + VAULT_TOKEN_TTL
+        auth = {"client_token": token, "lease_duration": VAULT_TOKEN_TTL, "renewable": True}
+        self.send_json(200, {"auth": auth})
+
+    def vault_login(self, request):
+        if request.get("role") != VAULT_ROLE or request.get("jwt") != VAULT_JWT:
+            return self.send_json(403, {"errors": ["permission denied"]})
+        self.vault_auth(secrets.token_hex(8))
+
+    def vault_renew(self):
+        token = self.vault_token()
+        if not token:
+            return self.send_json(403, {"errors": ["permission denied"]})
+        self.vault_auth(token)
+
+    def vault_lookup(self):
+        token = self.vault_token()
+        if not token:
+            return self.send_json(403, {"errors": ["permission denied"]})
+        ttl = int(vault_tokens[token] - time.time())
+        self.send_json(200, {"data": {"ttl": ttl, "renewable": True}})
+
+    def vault_read(self, path):
+        if not self.vault_token():
+            return self.send_json(403, {"errors": ["permission denied"]})
+        if path not in vault_secrets:
+            return self.send_json(404, {"errors": []})
+        self.send_json(200, {"data": {"data": vault_secrets[path], "metadata": {"version": 1}}})
+
+    def azure_chat(self, request):
+        url = urlparse(self.path)
+        if parse_qs(url.query).get("api-version") != ["2024-02-01"]:
+            return self.send_json(404, {"error": {"code": "404", "message": "unknown api-version"}})
+        if self.headers.get("api-key") != STUB_TOKEN:
+            return self.send_json(401, {"error": {"code": "401", "message": "bad api-key"}})
+        if request["messages"][-1]["content"] == "content_filter":
+            error = {
+                "code": "content_filter",
+                "message": "The response was filtered",
+                "innererror": {"code": "ResponsibleAIPolicyViolation"},
+            }
+            return self.send_json(400, {"error": error})
+        if request["messages"][-1]["content"] == "rate_limit":
+            error = {"code": "429", "message": "Rate limit is exceeded"}
+            return self.send_json(429, {"error": error}, headers={"Retry-After": "7", "retry-after-ms": "7000"})
+        self.openai_chat(request, authenticated=False, tenant=False)
+
+    def azure_models(self):
+        url = urlparse(self.path)
+        if parse_qs(url.query).get("api-version") != ["2024-02-01"]:
+            return self.send_json(404, {"error": {"code": "404", "message": "unknown api-version"}})
+        if self.headers.get("api-key") != STUB_TOKEN:
+            return self.send_json(401, {"error": {"code": "401", "message": "bad api-key"}})
+        self.send_json(200, {"object": "list", "data": [{"id": "gpt-4o", "object": "model"}]})
+
+    def cohere_model(self, model):
+        if self.headers.get("Authorization") != f"Bearer {STUB_TOKEN}":
+            return self.send_json(401, {"message": "invalid api token"})
+        if model != "command-r":
+            return self.send_json(404, {"message": f"model {model} not found"})
+        self.send_json(200, {"name": model, "endpoints": ["chat"]})
+
+    def cohere_chat(self, request):
+        if self.headers.get("Authorization") != f"Bearer {STUB_TOKEN}":
+            return self.send_json(401, {"message": "invalid api token"})
+        if request["message"] == "rate_limit":
+            return self.send_json(429, {"message": "too many requests"}, headers={"Retry-After": "7"})
+        roles = [message["role"] for message in request["chat_history"]]
+        if roles != ["USER", "CHATBOT"] * (len(roles) // 2):
+            return self.send_json(400, {"message": f"unexpected roles {roles}"})
+
+        generation = cohere_generation(request)
+        if not request.get("stream"):
+            return self.send_json(200, {"text": generation, "finish_reason": "COMPLETE"})
+
+        self.send_response(200)
+        self.send_header("Content-Type", "application/stream+json")
+        self.send_header("Connection", "close")
+        self.end_headers()
+        events = [{"is_finished": False, "event_type": "stream-start"}]
+        events += [
+            {"is_finished": False, "event_type": "text-generation", "text": token}
+            for token in tokenize(generation)
+        ]
+        events.append({"is_finished": True, "event_type": "stream-end", "finish_reason": "COMPLETE"})
+        for event in events:
+            self.wfile.write(f"{json.dumps(event)}\n".encode())
+            self.wfile.flush()
+        self.close_connection = True
+
+    def anthropic_error(self, status, error_type, message, headers=None):
+        error = {"type": error_type, "message": message}
+        self.send_json(status, {"type": "error", "error": error}, headers=headers)
+
+    def anthropic_model(self, model):
+        if self.headers.get("x-api-key") != STUB_TOKEN:
+            return self.anthropic_error(401, "authentication_error", "invalid x-api-key")
+        if model != "claude-3-haiku-20240307":
+            return self.anthropic_error(404, "not_found_error", f"model: {model}")
+        self.send_json(200, {"type": "model", "id": model})
+
+    def anthropic_messages(self, request):
+        if self.headers.get("x-api-key") != STUB_TOKEN:
+            return self.anthropic_error(401, "authentication_error", "invalid x-api-key")
+        if not self.headers.get("anthropic-version"):
+            return self.anthropic_error(400, "invalid_request_error", "anthropic-version header is required")
+        roles = [message["role"] for message in request["messages"]]
+        if roles != ["user", "assistant"] * (len(roles) // 2) + ["user"]:
+            return self.anthropic_error(400, "invalid_request_error", f"unexpected roles {roles}")
+        prompt = request["messages"][-1]["content"]
+        if prompt == "rate_limit":
+            return self.anthropic_error(429, "rate_limit_error", "Rate limited", headers={"Retry-After": "7"})
+        if prompt == "overloaded":
+            return self.anthropic_error(529, "overloaded_error", "Overloaded")
+
+        generation = anthropic_generation(request)
+        if not request.get("stream"):
+            content = [{"type": "text", "text": generation}]
+            return self.send_json(200, {"type": "message", "role": "assistant", "content": content})
+        events = [json.dumps({"type": "message_start", "message": {"role": "assistant", "content": []}})]
+        events += [
+            json.dumps({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": token}})
+            for token in tokenize(generation)
+        ]
+        if prompt == "stream_error":
+            events.append(json.dumps({"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}}))
+        else:
+            events.append(json.dumps({"type": "message_stop"}))
+        self.send_events(events)
+
+    def gemini_model(self, model):
+        if self.headers.get("x-goog-api-key") != STUB_TOKEN:
+            return self.send_json(400, {"error": {"code": 400, "message": "API key not valid"}})
+        if model != "gemini-1.5-flash":
+            return self.send_json(404, {"error": {"code": 404, "message": f"models/{model} is not found"}})
+        self.send_json(200, {"name": f"models/{model}"})
+
+    def gemini_generate(self, request):
+        if self.headers.get("x-goog-api-key") != STUB_TOKEN:
+            return self.send_json(400, {"error": {"code": 400, "message": "API key not valid"}})
+        roles = [content["role"] for content in request["contents"]]
+        if roles != ["user", "model"] * (len(roles) // 2) + ["user"]:
+            return self.send_json(400, {"error": {"code": 400, "message": f"unexpected roles {roles}"}})
+        if request["contents"][-1]["parts"][0]["text"] == "blocked":
+            return self.send_json(200, {"promptFeedback": {"blockReason": "SAFETY"}})
+        if request["contents"][-1]["parts"][0]["text"] == "rate_limit":
+            error = {"code": 429, "message": "Resource has been exhausted", "status": "RESOURCE_EXHAUSTED"}
+            return self.send_json(429, {"error": error}, headers={"Retry-After": "7"})
+
+        generation = gemini_generation(request)
+        if not self.path.endswith(":streamGenerateContent?alt=sse"):
+            content = {"role": "model", "parts": [{"text": generation}]}
+            return self.send_json(200, {"candidates": [{"content": content, "finishReason": "STOP"}]})
+        chunks = [
+            json.dumps({"candidates": [{"content": {"role": "model", "parts": [{"text": token}]}}]})
+            for token in tokenize(generation)
+        ]
+        chunks.append(json.dumps({"candidates": [{"content": {"role": "model", "parts": []}, "finishReason": "STOP"}]}))
+        self.send_events(chunks)
+
+    def ollama_chat(self, request):
+        if request["messages"][-1]["content"] == "rate_limit":
+            error = "server busy, please try again.  maximum pending requests exceeded"
+            return self.send_json(429, {"error": error}, headers={"Retry-After": "7"})
+        generation = openai_generation(request)
+        if not request.get("stream", True):
+            message = {"role": "assistant", "content": generation}
+            return self.send_json(200, {"model": request["model"], "message": message, "done": True})
+
+        self.send_response(200)
+        self.send_header("Content-Type", "application/x-ndjson")
+        self.send_header("Connection", "close")
+        self.end_headers()
+        for token in tokenize(generation):
+            message = {"role": "assistant", "content": token}
+            line = json.dumps({"model": request["model"], "message": message, "done": False})
+            self.wfile.write(f"{line}\n".encode())
+            self.wfile.flush()
+        done = {"model": request["model"], "message": {"role": "assistant", "content": ""}, "done": True}
+        self.wfile.write(f"{json.dumps(done)}\n".encode())
+        self.close_connection = True
+
+    def tgi_generate(self, request):
+        """Rejects inputs with "too long" or "bad parameter" in them the way TGI validates requests,
+        and turns away inputs with "overloaded" in them the way a busy TGI does"""
+        if "too long" in request["inputs"]:
+            error = "Input validation error: `inputs` must have less than 1024 tokens. Given: 1500"
+            return self.send_json(422, {"error": error, "error_type": "validation"})
+        if "bad parameter" in request["inputs"]:
+            error = "Input validation error: `temperature` must be strictly positive"
+            return self.send_json(422, {"error": error, "error_type": "validation"})
+        if "overloaded" in request["inputs"]:
+            body = {"error": "Model is overloaded", "error_type": "overloaded"}
+            return self.send_json(429, body, headers={"Retry-After": "7"})
+        generation = tgi_generation(request)
+        body = {"generated_text": generation}
+        if request.get("parameters", {}).get("details"):
+            body["details"] = {
+                "finish_reason": "eos_token",
+                "generated_tokens": len(tokenize(generation)),
+                "seed": None,
+            }
+        self.send_json(200, body)
+
+    def tgi_generate_stream(self, request):
+        tokens = [
+            json.dumps({"token": {"id": 0, "text": token, "logprob": 0.0, "special": False}})
+            for token in tokenize(tgi_generation(request))
+        ]
+        end = json.dumps({"token": {"id": 1, "text": "</s>", "logprob": 0.0, "special": True}})
+        self.send_events(tokens + [end])
+
+    def openai_models(self, authenticated):
+        if authenticated and self.headers.get("Authorization") != f"Bearer {STUB_TOKEN}":
+            return self.send_json(401, {"error": {"message": "bad token"}})
+        if self.headers.get("X-Stub-Tenant") != "ctf":
+            return self.send_json(400, {"error": {"message": "missing tenant header"}})
+        self.send_json(200, {"object": "list", "data": [{"id": "stub", "object": "model"}]})
+
+    def flaky_chat(self, request):
+        """Fails a prompt's first attempt with a 503 and a Retry-After, and its second with a 429"""
+        prompt = request["messages"][-1]["content"]
+        attempt = flaky_attempts.get(prompt, 0) + 1
+        flaky_attempts[prompt] = attempt
+        if attempt == 1:
+            payload = b'{"error": {"message": "overloaded"}}'
+            self.send_response(503)
+            self.send_header("Content-Type", "application/json")
+            self.send_header("Content-Length", str(len(payload)))
+            self.send_header("Retry-After", "1")
+            self.end_headers()
+            return self.wfile.write(payload)
+        if attempt == 2:
+            return self.send_json(429, {"error": {"message": "slow down"}})
+        self.openai_chat(request, authenticated=False)
+
+    def stall_chat(self, request):
+        """Streams the first token, then stalls past the read timeout of the models using it"""
+        first = tokenize(openai_generation(request))[0]
+        self.send_response(200)
+        self.send_header("Content-Type", "text/event-stream")
+        self.send_header("Connection", "close")
+        self.end_headers()
+        chunk = json.dumps({"choices": [{"index": 0, "delta": {"content": first}}]})
+        self.wfile.write(f"data: {chunk}\n\n".encode())
+        self.wfile.flush()
+        time.sleep(SLOW_SECONDS)
+        self.close_connection = True
+
+    def keys_chat(self, request):
+        """Rate limits the limited key for a minute, and answers any other valid key"""
+        if self.headers.get("Authorization") == f"Bearer {LIMITED_TOKEN}":
+            payload = b'{"error": {"message": "rate limited"}}'
+            self.send_response(429)
+            self.send_header("Content-Type", "application/json")
+            self.send_header("Content-Length", str(len(payload)))
+            self.send_header("Retry-After", "60")
+            self.end_headers()
+            return self.wfile.write(payload)
+        self.openai_chat(request, authenticated=True)
+
+    def openai_chat(self, request, authenticated, tenant=True, generation=None):
+        if authenticated and self.headers.get("Authorization") != f"Bearer {STUB_TOKEN}":
+            return self.send_json(401, {"error": {"message": "bad token"}})
+        if tenant and self.headers.get("X-Stub-Tenant") != "ctf":
+            return self.send_json(400, {"error": {"message": "missing tenant header"}})
+
+        generation = generation or openai_generation(request)
+        if request.get("stream"):
+            chunks = [
+                json.dumps({"choices": [{"index": 0, "delta": {"content": token}}]})
+                for token in tokenize(generation)
+            ]
+            return self.send_events(chunks + ["[DONE]"])
+
+        self.send_json(
+            200,
+            {
+                "id": "chatcmpl-stub",
+                "object": "chat.completion",
+                "created": int(time.time()),
+                "model": request["model"],
+                "choices": [
+                    {
+                        "index": 0,
+                        "message": {"role": "assistant", "content": generation},
+                        "finish_reason": "stop",
+                    }
+                ],
+                "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2},
+            },
+        )
+
+
+if __name__ == "__main__":
+    ThreadingHTTPServer(("0.0.0.0", 8080), StubHandler).serve_forever()